@@ -1,4 +1,5 @@
 use pelite::resources::version_info::{Language, VersionInfo};
+use sha2::{Digest, Sha256};
 use std::env;
 
 fn read_pe_version_info<'a>(image: &'a [u8]) -> Option<VersionInfo<'a>> {
@@ -23,6 +24,20 @@ fn detect_hachimi_version() {
     );
 }
 
+fn embed_payload_hashes() {
+    const PAYLOADS: &[(&str, &str)] = &[
+        ("hachimi.dll", "HACHIMI_DLL_SHA256"),
+        ("cellar.dll", "CELLAR_DLL_SHA256"),
+        ("umamusume.patch.zst", "STEAM_PATCH_SHA256"),
+    ];
+
+    for (file_name, env_name) in PAYLOADS {
+        println!("cargo:rerun-if-changed={}", file_name);
+        let data = std::fs::read(file_name).unwrap_or_else(|_| panic!("{} in project root", file_name));
+        println!("cargo:rustc-env={}={:x}", env_name, Sha256::digest(&data));
+    }
+}
+
 fn compile_resources() {
     println!("cargo:rerun-if-changed=assets");
 
@@ -62,6 +77,7 @@ fn main() {
     println!("cargo:rerun-if-env-changed=INSTALLER_REPO_URL");
 
     detect_hachimi_version();
+    embed_payload_hashes();
     compile_resources();
     set_repository_info();
 }
\ No newline at end of file
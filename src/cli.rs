@@ -1,15 +1,18 @@
-use std::path::{Path, PathBuf};
+use std::{cell::RefCell, io::{IsTerminal, Write}, path::{Path, PathBuf}};
 
 use crate::i18n::t;
 use windows::{
     core::HSTRING,
-    Win32::UI::{
-        Shell::ShellExecuteW,
-        WindowsAndMessaging::{MessageBoxW, IDCANCEL, MB_ICONERROR, MB_ICONINFORMATION, MB_OK, MB_OKCANCEL, SW_NORMAL}
-    }
+    Win32::UI::{Shell::ShellExecuteW, WindowsAndMessaging::SW_NORMAL}
 };
 
-use crate::{installer::{self, Installer, Target}, updater::UpdateStatus, utils};
+use crate::{
+    installer::{self, DeleteMode, GameVersion, Installer, LoadLikelihood, ProgressCallback, SteamStrategy, Target, UninstallOptions},
+    payload::PayloadOverride,
+    prompt::{MessageBoxPrompter, NoopPrompter, Prompter, RetryCancel},
+    updater::{UpdateChannel, UpdateStatus},
+    utils
+};
 
 #[derive(Default)]
 struct Args {
@@ -22,24 +25,67 @@ struct Args {
     launch_game: bool,
     game_args: Vec<String>,
     pre_install: bool,
-    post_install: bool
+    post_install: bool,
+    permanent_delete: bool,
+    force: bool,
+    open_folder: bool,
+    check_load: bool,
+    unattended: bool,
+    quiet: bool,
+    no_color: bool,
+    dll_override: Option<PathBuf>,
+    dll_override_sha256: Option<String>,
+    cellar_override: Option<PathBuf>,
+    cellar_override_sha256: Option<String>,
+    beta_exes: Vec<(GameVersion, String)>,
+    preserved_data: Vec<PathBuf>,
+    steam_app_id: Option<u32>,
+    temp_dir: Option<PathBuf>,
+    steam_dir: Option<PathBuf>,
+    steam_strategy: Option<SteamStrategy>,
+    game_version: Option<GameVersion>,
+    enable_dll_redirection: bool,
+    keep_exe_backup: bool,
+    vdf_audit_log: bool,
+    config: Option<PathBuf>,
+    migrate_from: Option<PathBuf>
 }
 
 enum Command {
     Install,
-    Uninstall
+    Uninstall,
+    Migrate,
+    Reinstall,
+    Repatch,
+    Verify,
+    Path,
+    Files,
+    Fingerprint,
+    AvExclusions,
+    ExportConfig,
+    List,
+    UninstallAll,
+    CleanBackups
 }
 
 #[inline]
-fn require_next_arg(args: &mut std::env::Args) -> String {
-    args.next().unwrap_or_else(|| std::process::exit(128))
+fn require_next_arg(args: &mut std::env::ArgsOs) -> String {
+    args.next()
+        .and_then(|arg| arg.into_string().ok())
+        .unwrap_or_else(|| std::process::exit(128))
+}
+
+// Paths don't have to be valid Unicode, so they're taken as is
+#[inline]
+fn require_next_path(args: &mut std::env::ArgsOs) -> PathBuf {
+    args.next().map(PathBuf::from).unwrap_or_else(|| std::process::exit(128))
 }
 
 impl Args {
     fn parse() -> Args {
         let mut args = Args::default();
 
-        let mut iter = std::env::args();
+        let mut iter = std::env::args_os();
         iter.next();
 
         let mut in_game_args = false;
@@ -49,16 +95,28 @@ impl Args {
             };
 
             if in_game_args {
-                args.game_args.push(arg);
+                args.game_args.push(arg.into_string().unwrap_or_else(|_| std::process::exit(128)));
                 continue;
             }
 
-            match arg.as_str() {
+            match arg.to_str().unwrap_or_default() {
                 "install" => args.command = Some(Command::Install),
                 "uninstall" => args.command = Some(Command::Uninstall),
+                "migrate" => args.command = Some(Command::Migrate),
+                "reinstall" => args.command = Some(Command::Reinstall),
+                "repatch" => args.command = Some(Command::Repatch),
+                "verify" => args.command = Some(Command::Verify),
+                "path" => args.command = Some(Command::Path),
+                "files" => args.command = Some(Command::Files),
+                "fingerprint" => args.command = Some(Command::Fingerprint),
+                "av-exclusions" => args.command = Some(Command::AvExclusions),
+                "export-config" => args.command = Some(Command::ExportConfig),
+                "list" => args.command = Some(Command::List),
+                "uninstall-all" => args.command = Some(Command::UninstallAll),
+                "clean-backups" => args.command = Some(Command::CleanBackups),
 
 
-                "--install-dir" => args.install_dir = Some(require_next_arg(&mut iter).into()),
+                "--install-dir" => args.install_dir = Some(require_next_path(&mut iter)),
                 "--target" => args.target = Some(require_next_arg(&mut iter)),
                 "--explicit-target" => {
                     let dll_name = require_next_arg(&mut iter);
@@ -68,11 +126,50 @@ impl Args {
                         .unwrap_or_else(|| std::process::exit(128))
                     );
                 },
+                "--from" => args.migrate_from = Some(require_next_path(&mut iter)),
+                "--dll-override" => args.dll_override = Some(require_next_path(&mut iter)),
+                "--cellar-override" => args.cellar_override = Some(require_next_path(&mut iter)),
+                "--dll-override-sha256" => args.dll_override_sha256 = Some(require_next_arg(&mut iter)),
+                "--cellar-override-sha256" => args.cellar_override_sha256 = Some(require_next_arg(&mut iter)),
+                "--beta-exe" => {
+                    let value = require_next_arg(&mut iter);
+                    let (version, exe_name) = value.split_once(':')
+                        .and_then(|(version, exe_name)| Some((GameVersion::from_id(version)?, exe_name.to_owned())))
+                        .unwrap_or_else(|| std::process::exit(128));
+                    args.beta_exes.push((version, exe_name));
+                },
+                "--preserve" => args.preserved_data.push(require_next_path(&mut iter)),
+                "--steam-app-id" => args.steam_app_id = Some(require_next_arg(&mut iter).parse().unwrap_or_else(|_| std::process::exit(128))),
+                "--config" => args.config = Some(require_next_path(&mut iter)),
+                "--temp-dir" => args.temp_dir = Some(require_next_path(&mut iter)),
+                "--steam-dir" => args.steam_dir = Some(require_next_path(&mut iter)),
+                "--game-version" => args.game_version = Some(
+                    GameVersion::from_id(&require_next_arg(&mut iter)).unwrap_or_else(|| std::process::exit(128))
+                ),
+                "--steam-strategy" => args.steam_strategy = Some(
+                    SteamStrategy::from_id(&require_next_arg(&mut iter)).unwrap_or_else(|| std::process::exit(128))
+                ),
                 "--sleep" => args.sleep = Some(require_next_arg(&mut iter).parse().unwrap_or_else(|_| std::process::exit(128))),
                 "--prompt-for-game-exit" => args.prompt_for_game_exit = true,
                 "--launch-game" => args.launch_game = true,
                 "--pre-install" => args.pre_install = true,
                 "--post-install" => args.post_install = true,
+                "--permanent-delete" => args.permanent_delete = true,
+                "--force" => args.force = true,
+                "--open-folder" => args.open_folder = true,
+                "--enable-dll-redirection" => args.enable_dll_redirection = true,
+                "--keep-exe-backup" => args.keep_exe_backup = true,
+                "--vdf-audit-log" => args.vdf_audit_log = true,
+                "--check-load" => args.check_load = true,
+                "--unattended" => args.unattended = true,
+                "--quiet" => args.quiet = true,
+                "--no-color" => args.no_color = true,
+                // Read by update_channel() before the update check
+                "--update-channel" => {
+                    require_next_arg(&mut iter);
+                },
+                // Read by wants_minidump() when installing the panic hook
+                "--minidump" => {},
                 "--" => in_game_args = true,
 
                 _ => {
@@ -86,12 +183,67 @@ impl Args {
     }
 }
 
+/// The channel given by `--update-channel`, which is needed before the other arguments are parsed.
+pub fn update_channel() -> UpdateChannel {
+    let mut iter = std::env::args_os()
+        .take_while(|arg| arg != "--")
+        .skip_while(|arg| arg != "--update-channel")
+        .skip(1);
+    match iter.next() {
+        Some(id) => id.to_str()
+            .and_then(UpdateChannel::from_id)
+            .unwrap_or_else(|| std::process::exit(128)),
+        None => UpdateChannel::default()
+    }
+}
+
+pub fn wants_minidump() -> bool {
+    std::env::args_os()
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--minidump")
+}
+
+fn create_prompter(unattended: bool) -> Box<dyn Prompter> {
+    if unattended {
+        Box::new(NoopPrompter)
+    } else {
+        Box::new(MessageBoxPrompter::new(None))
+    }
+}
+
+/// Prints the stage and percentage on a single console line, which is rewritten as it changes.
+fn console_progress(color: bool) -> ProgressCallback {
+    let last = RefCell::new((String::new(), u8::MAX));
+    Box::new(move |stage, percent| {
+        let mut last = last.borrow_mut();
+        if last.0 == stage && last.1 == percent {
+            return;
+        }
+        if last.0 != stage && last.1 != 100 && !last.0.is_empty() {
+            println!();
+        }
+        *last = (stage.to_owned(), percent);
+
+        let mut stdout = std::io::stdout().lock();
+        if color {
+            _ = write!(stdout, "\r\x1b[2K\x1b[36m{:>3}%\x1b[0m {}", percent, stage);
+        } else {
+            _ = write!(stdout, "\r{:>3}% {}", percent, stage);
+        }
+        if percent == 100 {
+            _ = writeln!(stdout);
+        }
+        _ = stdout.flush();
+    })
+}
+
 pub fn run(update_status: &UpdateStatus) -> Result<bool, installer::Error> {
     match update_status {
-        UpdateStatus::Updated(version) => println!(
-            "{}",
-            t!("cli.update_status.success", version = version.to_string())
-        ),
+        UpdateStatus::Updated(info) => if info.prerelease {
+            println!("{}", t!("cli.update_status.success_prerelease", version = info.date))
+        } else {
+            println!("{}", t!("cli.update_status.success", version = info.date))
+        },
         UpdateStatus::NotNeeded => {
             println!("{}", t!("cli.update_status.not_needed"))
         }
@@ -102,24 +254,73 @@ pub fn run(update_status: &UpdateStatus) -> Result<bool, installer::Error> {
     }
 
     let mut args = Args::parse();
+
+    if let Some(Command::List) = args.command {
+        let mut installer = Installer::default();
+        installer.beta_exes = args.beta_exes;
+        if let Some(dir) = args.steam_dir {
+            installer.set_steam_dir(dir)?;
+        }
+        installer.detect_install_dirs();
+        for candidate in installer.candidate_dirs() {
+            println!("{}", candidate.get_display_label());
+        }
+        return Ok(true);
+    }
+
+    if let Some(Command::UninstallAll) = args.command {
+        let mut installer = Installer::default();
+        installer.beta_exes = args.beta_exes;
+        installer.prompter = create_prompter(args.unattended);
+        if let Some(dir) = args.steam_dir {
+            installer.set_steam_dir(dir)?;
+        }
+        installer.detect_install_dirs();
+        let options = UninstallOptions {
+            delete_mode: if args.permanent_delete { DeleteMode::Permanent } else { DeleteMode::RecycleBin },
+            force: false
+        };
+
+        let mut first_error = None;
+        for (version, report) in installer.uninstall_all_detected(options) {
+            match report.result {
+                Ok(_) => println!("{}: {}", version.display_name(), report.path.display()),
+                Err(e) => {
+                    eprintln!("{}: {} - {}", version.display_name(), report.path.display(), e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        return first_error.map_or(Ok(true), Err);
+    }
     
     if let Some(command) = args.command {
+        let prompter = create_prompter(args.unattended);
+
+        if matches!(command, Command::Migrate) && args.migrate_from.is_none() {
+            prompter.error(&t!("cli.installer_title"), &t!("cli.missing_argument", arg = "--from"));
+            std::process::exit(128);
+        }
+
+        // Enabling DLL redirection writes to HKLM
+        let needs_admin = match command {
+            Command::Install => args.post_install,
+            Command::Reinstall | Command::Migrate => true,
+            _ => false
+        };
+        if needs_admin && !utils::is_elevated() {
+            let e = utils::relaunch_elevated();
+            prompter.warn(&t!("cli.installer_title"), &t!("cli.not_elevated", error = e));
+        }
+
         if let Some(sleep) = args.sleep {
             std::thread::sleep(std::time::Duration::from_millis(sleep));
         }
 
         if args.prompt_for_game_exit {
             while utils::is_game_running() {
-                unsafe {
-                    let res = MessageBoxW(
-                        None,
-                        &HSTRING::from(t!("cli.game_running")),
-                        &HSTRING::from(t!("cli.installer_title")),
-                        MB_ICONINFORMATION | MB_OKCANCEL
-                    );
-                    if res == IDCANCEL {
-                        return Ok(true);
-                    }
+                if prompter.retry_cancel(&t!("cli.installer_title"), &t!("cli.game_running")) == RetryCancel::Cancel {
+                    return Ok(true);
                 }
             }
         }
@@ -133,66 +334,230 @@ pub fn run(update_status: &UpdateStatus) -> Result<bool, installer::Error> {
                 }
             }
             None
-        }).unwrap_or_else(|| {
-            unsafe {
-                MessageBoxW(
-                    None,
-                    &HSTRING::from(t!("cli.failed_determine_target")),
-                    &HSTRING::from(t!("cli.installer_title")),
-                    MB_ICONERROR | MB_OK
-                );
-            }
+        });
+        // The target can come from the config instead
+        if explicit_target.is_none() && (args.config.is_none() || args.target.is_some()) {
+            prompter.error(&t!("cli.installer_title"), &t!("cli.failed_determine_target"));
             std::process::exit(128);
+        }
+
+        let mut installer = Installer::new(explicit_target.unwrap_or_default(), None);
+        installer.beta_exes = args.beta_exes;
+        if let Some(path) = &args.config {
+            if let Err(e) = installer.import_config(path) {
+                prompter.error(&t!("cli.installer_title"), &e.to_string());
+                return Err(e);
+            }
+        }
+
+        // Arguments take precedence over the config
+        if let Some(target) = explicit_target {
+            installer.target = target;
+            installer.custom_target = args.target;
+        }
+        installer.force_custom_target = args.force;
+        installer.dll_override = args.dll_override.map(|path| PayloadOverride {
+            path,
+            sha256: args.dll_override_sha256
+        });
+        installer.cellar_override = args.cellar_override.map(|path| PayloadOverride {
+            path,
+            sha256: args.cellar_override_sha256
         });
+        installer.preserved_data.extend(args.preserved_data);
+        installer.steam_app_id = args.steam_app_id.or(installer.steam_app_id);
+        installer.temp_dir = args.temp_dir.or(installer.temp_dir);
+        installer.steam_strategy = args.steam_strategy.unwrap_or(installer.steam_strategy);
+        installer.auto_enable_dll_redirection = args.enable_dll_redirection;
+        installer.keep_exe_backup = args.keep_exe_backup || installer.keep_exe_backup;
+        installer.vdf_audit_log = args.vdf_audit_log || installer.vdf_audit_log;
+        installer.prompter = create_prompter(args.unattended);
+        if !args.quiet && std::io::stdout().is_terminal() {
+            installer.progress = Some(console_progress(!args.no_color));
+        }
 
-        let mut installer = Installer::new(explicit_target, args.target);
+        if let Some(dir) = args.steam_dir {
+            if let Err(e) = installer.set_steam_dir(dir) {
+                prompter.error(&t!("cli.installer_title"), &e.to_string());
+                return Err(e);
+            }
+        }
 
         if let Some(dir) = args.install_dir {
+            let dir = match installer.find_game_root(&dir) {
+                Some(root) if root != dir => {
+                    println!("{}", t!("cli.using_game_root", path = root.display()));
+                    root
+                },
+                _ => dir
+            };
             if let Err(e) = installer.set_install_dir(dir) {
-                unsafe { MessageBoxW(None, &HSTRING::from(e.to_string()), &HSTRING::from(t!("cli.installer_title")), MB_ICONERROR | MB_OK); }
+                prompter.error(&t!("cli.installer_title"), &e.to_string());
                 return Err(e);
             }
-        } else {
+            // The explicit dir decides the version, so the requested one can only be checked against it
+            if let Some(version) = args.game_version.filter(|version| installer.game_version() != Some(*version)) {
+                let e = installer::Error::GameVersionNotFound(version);
+                prompter.error(&t!("cli.installer_title"), &e.to_string());
+                return Err(e);
+            }
+        } else if let Some(version) = args.game_version {
+            installer.detect_install_dirs();
+            if installer.set_game_version(version).is_none() {
+                let e = installer::Error::GameVersionNotFound(version);
+                prompter.error(&t!("cli.installer_title"), &e.to_string());
+                return Err(e);
+            }
+        } else if installer.install_dir().is_none() {
             installer.detect_install_dir();
         }
 
+        if args.prompt_for_game_exit && installer.game_version() == Some(GameVersion::DMM) {
+            while utils::is_dmm_launcher_running() {
+                if prompter.retry_cancel(&t!("cli.installer_title"), &t!("cli.dmm_launcher_running")) == RetryCancel::Cancel {
+                    return Ok(true);
+                }
+            }
+        }
+
+        if installer.is_beta_build() {
+            let version = installer.get_game_version_info()
+                .and_then(|info| info.version)
+                .unwrap_or_else(|| "Unknown".to_owned());
+            println!("{}", t!("cli.beta_build_detected", exe_name = installer.game_exe_name(), version = version));
+        }
+
+        let uninstall_options = UninstallOptions {
+            delete_mode: if args.permanent_delete {
+                DeleteMode::Permanent
+            } else {
+                DeleteMode::RecycleBin
+            },
+            force: args.force
+        };
+
         let res: Result<(), installer::Error> = (|| {
             match command {
                 Command::Install => {
+                    if let Some(warning) = installer.effective_install_method(installer.target).get_warning() {
+                        eprintln!("{}", warning);
+                    }
                     if args.pre_install {
                         installer.pre_install()?;
                     }
+                    if installer.progress.is_some() {
+                        if let Some(estimate) = installer.estimate_patch() {
+                            println!("{}", t!(
+                                "cli.patch_estimate",
+                                size = utils::format_size(estimate.size),
+                                seconds = estimate.seconds
+                            ));
+                        }
+                    }
                     installer.install()?;
                     if args.post_install {
                         installer.post_install()?;
                     }
+                    if args.check_load {
+                        match installer.will_dll_load() {
+                            LoadLikelihood::Yes => println!("{}", t!("cli.load_check.yes")),
+                            LoadLikelihood::AfterReboot => println!("{}", t!("cli.load_check.after_reboot")),
+                            LoadLikelihood::No(reason) => println!("{}", t!("cli.load_check.no", reason = reason))
+                        }
+                    }
+                    if args.open_folder {
+                        if let Some(dir) = installer.get_current_target_dir() {
+                            utils::open_folder(&dir);
+                        }
+                    }
                 },
                 Command::Uninstall => {
-                    installer.uninstall()?;
-                }
+                    let follow_up = installer.uninstall(uninstall_options)?;
+                    for leftover in installer.verify_uninstall() {
+                        eprintln!("{}", t!("cli.uninstall_leftover", path = leftover));
+                    }
+                    if let Some(message) = follow_up.get_message() {
+                        println!("{}", message);
+                    }
+                },
+                Command::Migrate => {
+                    let from = args.migrate_from.as_ref().expect("checked after parsing");
+                    let to = installer.install_dir().cloned().ok_or(installer::Error::NoInstallDir)?;
+                    let report = installer.migrate(from, &to, uninstall_options)?;
+                    if let Err(e) = &report.uninstall {
+                        eprintln!("{}", t!("cli.migrate_uninstall_failed", error = e.to_string()));
+                    }
+                    report.install.and(report.uninstall)?;
+                },
+                Command::Reinstall => {
+                    installer.reinstall(uninstall_options)?;
+                },
+                Command::Repatch => {
+                    installer.repatch()?;
+                },
+                Command::Verify => {
+                    let issues = installer.verify_install()?;
+                    if issues.is_empty() {
+                        println!("{}", t!("cli.verify_ok"));
+                    } else {
+                        for issue in &issues {
+                            println!("{}", issue);
+                        }
+                        // Distinguishes a broken install from the check itself failing, which exits with 1
+                        std::process::exit(2);
+                    }
+                },
+                Command::Path => {
+                    let path = installer.get_current_target_path().ok_or(installer::Error::NoInstallDir)?;
+                    let effective_method = installer.effective_install_method(installer.target);
+                    println!("{} ({})", path.display(), effective_method.method.name());
+                    if let Some(warning) = effective_method.get_warning() {
+                        eprintln!("{}", warning);
+                    }
+                },
+                Command::Files => {
+                    for path in installer.planned_files() {
+                        println!("{}", path.display());
+                    }
+                },
+                Command::Fingerprint => {
+                    let exe_path = installer.install_dir().ok_or(installer::Error::NoInstallDir)?.join(installer.game_exe_name());
+                    let fingerprint = utils::fingerprint_file(&exe_path).map_err(installer::Error::VerificationError)?;
+                    println!("{}", fingerprint);
+                },
+                Command::AvExclusions => {
+                    let path = Installer::av_exclusions_path();
+                    installer.write_av_exclusions(&path)?;
+                    println!("{}", path.display());
+                },
+                Command::CleanBackups => {
+                    for path in installer.clean_backup_files(uninstall_options.delete_mode)? {
+                        println!("{}", path.display());
+                    }
+                },
+                Command::ExportConfig => {
+                    println!("{}", installer.export_config());
+                },
+                Command::List | Command::UninstallAll => unreachable!()
             }
             Ok(())
         })();
 
         if let Err(e) = res {
-            unsafe { MessageBoxW(None, &HSTRING::from(e.to_string()), &HSTRING::from(t!("cli.installer_title")), MB_ICONERROR | MB_OK); }
+            prompter.error(&t!("cli.installer_title"), &e.to_string());
             return Err(e);
         }
 
         if args.launch_game {
             let game_dir = installer.install_dir().unwrap();
-            let exe_path = if game_dir.join("UmamusumePrettyDerby_Jpn.exe").is_file() {
-                game_dir.join("UmamusumePrettyDerby_Jpn.exe")
-            } else {
-                game_dir.join("umamusume.exe")
-            };
+            let exe_path = game_dir.join(installer.game_exe_name());
             unsafe {
                 ShellExecuteW(
                     None,
                     None,
-                    &HSTRING::from(exe_path.to_str().unwrap()),
+                    &HSTRING::from(exe_path.as_path()),
                     &HSTRING::from(args.game_args.join(" ")),
-                    &HSTRING::from(game_dir.to_str().unwrap()),
+                    &HSTRING::from(game_dir.as_path()),
                     SW_NORMAL
                 );
             }
@@ -0,0 +1,58 @@
+use std::{backtrace::Backtrace, fs::File, os::windows::io::AsRawHandle, path::Path};
+
+use crate::i18n::t;
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::HANDLE,
+        System::{
+            Diagnostics::Debug::{MiniDumpWriteDump, MiniDumpWithThreadInfo},
+            Threading::{GetCurrentProcess, GetCurrentProcessId}
+        },
+        UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR, MB_OK}
+    }
+};
+
+const REPORT_FILE_NAME: &str = "hachimi_installer_crash.txt";
+
+fn write_minidump(path: &Path) -> windows::core::Result<()> {
+    let file = File::create(path)?;
+    unsafe {
+        MiniDumpWriteDump(
+            GetCurrentProcess(),
+            GetCurrentProcessId(),
+            HANDLE(file.as_raw_handle()),
+            MiniDumpWithThreadInfo,
+            None,
+            None,
+            None
+        )
+    }
+}
+
+/// On panic, writes the message and backtrace to a file in the temp dir, plus a minidump
+/// next to it if `minidump` is set, and tells the user where to find them.
+/// Nothing is sent anywhere.
+pub fn install_panic_hook(minidump: bool) {
+    std::panic::set_hook(Box::new(move |info| {
+        let report = format!("{}\n\n{}", info, Backtrace::force_capture());
+        eprintln!("{}", report);
+
+        let report_path = std::env::temp_dir().join(REPORT_FILE_NAME);
+        if std::fs::write(&report_path, &report).is_err() {
+            return;
+        }
+        if minidump {
+            _ = write_minidump(&report_path.with_extension("dmp"));
+        }
+
+        unsafe {
+            MessageBoxW(
+                None,
+                &HSTRING::from(t!("crash.body", path = report_path.display())),
+                &HSTRING::from(t!("crash.title")),
+                MB_ICONERROR | MB_OK
+            );
+        }
+    }));
+}
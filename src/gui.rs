@@ -1,4 +1,4 @@
-use crate::{installer::{self, GameVersion, Installer}, resource::*, updater::UpdateStatus, utils};
+use crate::{installer::{self, GameVersion, InstallShape, Installer, LoadLikelihood, TargetScope, TargetStatus, UninstallOptions}, prompt::MessageBoxPrompter, resource::*, updater::UpdateStatus, utils};
 use crate::i18n::{self, SUPPORTED_LOCALES, t};
 use windows::{core::HSTRING, Win32::{
     Foundation::{HWND, LPARAM, WPARAM},
@@ -8,7 +8,7 @@ use windows::{core::HSTRING, Win32::{
         GetWindowLongPtrW, IsDialogMessageW, LoadIconW, MessageBoxW, PostQuitMessage, SendMessageW,
         SetWindowLongPtrW,SetWindowTextW, ShowWindow, TranslateMessage,
         CBN_SELCHANGE, CB_ADDSTRING, CB_DELETESTRING, CB_GETCURSEL, CB_INSERTSTRING, CB_RESETCONTENT, CB_SETCURSEL,
-        GWLP_USERDATA, ICON_BIG, IDOK, IDYES, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONWARNING,
+        GWLP_USERDATA, ICON_BIG, IDOK, IDYES, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONQUESTION, MB_ICONWARNING,
         MB_OK, MB_OKCANCEL, MB_YESNO, MSG, SW_SHOW, WM_CLOSE, WM_COMMAND, WM_INITDIALOG, WM_SETICON,
         SetTimer, KillTimer, WM_TIMER
     }}
@@ -45,8 +45,12 @@ pub fn run(update_status: UpdateStatus) -> Result<(), windows::core::Error> {
     }
 
     match update_status {
-        UpdateStatus::Updated(date) => {
-            let message = t!("gui.update_success_body", date = date);
+        UpdateStatus::Updated(info) => {
+            let message = if info.prerelease {
+                t!("gui.update_success_body_prerelease", date = info.date)
+            } else {
+                t!("gui.update_success_body", date = info.date)
+            };
             unsafe {
                 MessageBoxW(None, &HSTRING::from(message), &HSTRING::from(t!("gui.update_successful")), MB_ICONINFORMATION | MB_OK);
             }
@@ -69,7 +73,7 @@ pub fn run(update_status: UpdateStatus) -> Result<(), windows::core::Error> {
     }?;
     utils::center_window(dialog)?;
     let _ = unsafe { ShowWindow(dialog, SW_SHOW) };
-    installer.hwnd = Some(dialog);
+    installer.prompter = Box::new(MessageBoxPrompter::new(Some(dialog)));
 
     let mut message = MSG::default();
     while unsafe { GetMessageW(&mut message, None, 0, 0) }.as_bool() {
@@ -107,6 +111,44 @@ fn update_game_running_state(dialog: HWND) {
     }
 }
 
+/// Returns false if the user chose to stop because the game's launcher (DMM Game Player or Steam) is running.
+fn confirm_launcher_closed(dialog: HWND) -> bool {
+    let installer = get_installer(dialog);
+    let message = match installer.game_version() {
+        Some(GameVersion::DMM) if utils::is_dmm_launcher_running() => t!("gui.dmm_launcher_running"),
+        Some(GameVersion::Steam | GameVersion::SteamGlobal) if utils::is_steam_running() => t!("gui.steam_running"),
+        _ => return true
+    };
+
+    let res = unsafe {
+        MessageBoxW(
+            dialog,
+            &HSTRING::from(message),
+            &HSTRING::from(t!("gui.warning")),
+            MB_ICONWARNING | MB_OKCANCEL
+        )
+    };
+    res == IDOK
+}
+
+/// Returns the window to having no install location or game version selected.
+fn clear_selection(dialog: HWND) {
+    let installer = get_installer(dialog);
+    installer.clear_selection();
+
+    unsafe {
+        _ = SetWindowTextW(GetDlgItem(dialog, IDC_INSTALL_PATH).unwrap(), &HSTRING::new());
+        for id in [IDC_VERSION_DMM, IDC_VERSION_STEAM, IDC_VERSION_STEAM_GLOBAL] {
+            if let Ok(btn) = GetDlgItem(dialog, id) {
+                SendMessageW(btn, BM_SETCHECK, WPARAM(BST_UNCHECKED.0 as _), None);
+            }
+        }
+    }
+
+    refresh_all_target_labels(dialog);
+    update_target(dialog, unsafe { GetDlgItem(dialog, IDC_TARGET).unwrap() }, installer.target as _);
+}
+
 fn refresh_all_target_labels(dialog: HWND) {
     let installer = get_installer(dialog);
     let target_combo = unsafe { GetDlgItem(dialog, IDC_TARGET).unwrap() };
@@ -130,13 +172,21 @@ fn refresh_all_target_labels(dialog: HWND) {
 fn update_target(dialog: HWND, target_combo: HWND, index: usize) {
     let installer = get_installer(dialog);
     let target = installer::Target::VALUES[index];
-    let mut installed = false;
-    let label = if let Some(version_info) = installer.get_target_version_info(target) {
-        installed = true;
-        version_info.version.unwrap_or_else(|| "Unknown".to_owned())
-    }
-    else {
-        "None".to_owned()
+    let status = installer.get_target_status(target);
+    let installed = status.is_present();
+    let label = match status {
+        TargetStatus::Present(version_info) => {
+            let version = version_info.version.unwrap_or_else(|| "Unknown".to_owned());
+            // Only the selected target's files matter here
+            let shape = installer.install_dir()
+                .map(|dir| installer.detect_existing_install_shape(dir, TargetScope::Only(target)));
+            match shape {
+                Some(shape) if shape != InstallShape::NotInstalled => format!("{} ({})", version, shape.get_display_label()),
+                _ => version
+            }
+        },
+        TargetStatus::Unreadable(reason) => format!("Unknown ({})", reason),
+        TargetStatus::Absent => "None".to_owned()
     };
 
     let installed_static = unsafe { GetDlgItem(dialog, IDC_INSTALLED).unwrap() };
@@ -179,9 +229,11 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
             let steam_global_radio = unsafe { GetDlgItem(dialog, IDC_VERSION_STEAM_GLOBAL).ok() };
             let version_group = unsafe { GetDlgItem(dialog, IDC_VERSION_GROUP).unwrap() };
 
-            let has_dmm = installer.dmm_install_dir().is_some();
-            let has_steam = installer.steam_install_dir().is_some();
-            let has_steam_global = installer.steam_global_install_dir().is_some();
+            let candidates = installer.candidate_dirs();
+            let has_version = |version| candidates.iter().any(|candidate| candidate.version == version);
+            let has_dmm = has_version(GameVersion::DMM);
+            let has_steam = has_version(GameVersion::Steam);
+            let has_steam_global = has_version(GameVersion::SteamGlobal);
 
             let version_count = [has_dmm, has_steam, has_steam_global].iter().filter(|&&v| v).count();
 
@@ -208,7 +260,7 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
 
                 if let Some(path) = installer.install_dir() {
                     let install_path_edit = unsafe { GetDlgItem(dialog, IDC_INSTALL_PATH).unwrap() };
-                    _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.to_str().unwrap())) };
+                    _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.as_path())) };
                 }
 
                 let (dmm_check, steam_check, steam_global_check) = match initial_version {
@@ -229,7 +281,7 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
             } else {
                 if let Some(path) = installer.install_dir() {
                     let install_path_edit = unsafe { GetDlgItem(dialog, IDC_INSTALL_PATH).unwrap() };
-                    _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.to_str().unwrap())) };
+                    _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.as_path())) };
                 }
             }
 
@@ -322,6 +374,28 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                 }
             }
 
+            // A game update replaces the patched executable, which is the most common reason Hachimi stops loading
+            if installer.needs_repatch() {
+                let res = unsafe {
+                    MessageBoxW(
+                        dialog,
+                        &HSTRING::from(t!("gui.repatch_prompt")),
+                        &HSTRING::from(t!("gui.title")),
+                        MB_ICONINFORMATION | MB_YESNO
+                    )
+                };
+                if res == IDYES {
+                    match installer.repatch() {
+                        Ok(_) => unsafe {
+                            MessageBoxW(dialog, &HSTRING::from(t!("gui.repatch_ok")), &HSTRING::from(t!("gui.title")), MB_ICONINFORMATION | MB_OK);
+                        },
+                        Err(e) => unsafe {
+                            MessageBoxW(dialog, &HSTRING::from(e.to_string()), &HSTRING::from(t!("gui.error")), MB_ICONERROR | MB_OK);
+                        }
+                    }
+                }
+            }
+
             1
         },
 
@@ -351,7 +425,7 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
 
                     if let Some(path) = installer.set_game_version(version) {
                         let install_path_edit = unsafe { GetDlgItem(dialog, IDC_INSTALL_PATH).unwrap() };
-                        _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.to_str().unwrap())) };
+                        _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.as_path())) };
                     }
 
                     if let Ok(btn) = unsafe { GetDlgItem(dialog, IDC_VERSION_DMM) } {
@@ -398,15 +472,30 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                     let installer = get_installer(dialog);
                     let Some(path) = utils::open_select_folder_dialog(
                         dialog,
-                        installer.install_dir().filter(|p| p.is_dir())
+                        installer.suggested_browse_start()
                     ) else {
                         return 1;
                     };
 
+                    let path = match installer.find_game_root(&path) {
+                        Some(root) if root != path => {
+                            let res = unsafe {
+                                MessageBoxW(
+                                    dialog,
+                                    &HSTRING::from(t!("gui.use_game_root", path = root.display())),
+                                    &HSTRING::from(t!("gui.title")),
+                                    MB_ICONQUESTION | MB_YESNO
+                                )
+                            };
+                            if res == IDYES { root } else { path }
+                        },
+                        _ => path
+                    };
+
                     match installer.set_install_dir(path.clone()) {
                         Ok(_) => {
                             let install_path_edit = unsafe { GetDlgItem(dialog, IDC_INSTALL_PATH).unwrap() };
-                            _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.to_str().unwrap())) };
+                            _ = unsafe { SetWindowTextW(install_path_edit, &HSTRING::from(path.as_path())) };
 
                             // Update radio buttons to reflect the detected game version
                             if let Some(version) = installer.game_version() {
@@ -455,7 +544,7 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
 
                 IDC_INSTALL => {
                     let installer = get_installer(dialog);
-                    if let Some(target) = installer.get_hachimi_installed_target() {
+                    if let Some(target) = installer.get_hachimi_installed_target(TargetScope::All) {
                         if target != installer.target {
                             unsafe {
                                 MessageBoxW(
@@ -468,13 +557,48 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                             return 0;
                         }
                     }
-                    if installer.is_current_target_installed() {
+                    if let Some(warning) = installer.effective_install_method(installer.target).get_warning() {
                         let res = unsafe {
                             MessageBoxW(
                                 dialog,
-                                &HSTRING::from(t!("gui.replace_confirm", dll = installer.target.dll_name())),
-                                &HSTRING::from(t!("gui.install")),
-                                MB_ICONINFORMATION | MB_OKCANCEL
+                                &HSTRING::from(t!("gui.install_method_warning", warning = warning)),
+                                &HSTRING::from(t!("gui.warning")),
+                                MB_ICONWARNING | MB_OKCANCEL
+                            )
+                        };
+                        if res != IDOK {
+                            return 0;
+                        }
+                    }
+                    // Conflicts and replacing the current target are asked about together,
+                    // since a conflicting DLL is often the one being replaced
+                    let conflicts = installer.detect_conflicts();
+                    let replacing = installer.is_current_target_installed();
+                    if !conflicts.is_empty() || replacing {
+                        let mut message = Vec::new();
+                        if !conflicts.is_empty() {
+                            let list = conflicts.iter()
+                                .map(|c| c.get_display_label())
+                                .collect::<Vec<_>>()
+                                .join("\n");
+                            message.push(t!("gui.conflicts_detected", list = list));
+                        }
+                        message.push(if replacing {
+                            t!("gui.replace_confirm", dll = installer.target.dll_name())
+                        } else {
+                            t!("gui.continue_anyway")
+                        });
+                        let (title, icon) = if conflicts.is_empty() {
+                            (t!("gui.install"), MB_ICONINFORMATION)
+                        } else {
+                            (t!("gui.warning"), MB_ICONWARNING)
+                        };
+                        let res = unsafe {
+                            MessageBoxW(
+                                dialog,
+                                &HSTRING::from(message.join("\n\n")),
+                                &HSTRING::from(title),
+                                icon | MB_OKCANCEL
                             )
                         };
                         if res != IDOK {
@@ -501,12 +625,37 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                         return 0;
                     }
 
+                    if !confirm_launcher_closed(dialog) {
+                        return 0;
+                    }
+
+                    // Adding exclusions needs admin rights, so only offer it when we have them
+                    if utils::is_elevated() {
+                        let message = t!("gui.av_exclusions_prompt", path = Installer::av_exclusions_path().display());
+                        let res = unsafe { MessageBoxW(dialog, &HSTRING::from(message), &HSTRING::from(t!("gui.title")), MB_ICONQUESTION | MB_YESNO) };
+                        if res == IDYES {
+                            if let Err(e) = installer.add_av_exclusions() {
+                                unsafe { MessageBoxW(dialog, &HSTRING::from(t!("gui.av_exclusions_failed", error = e.to_string())), &HSTRING::from(t!("gui.warning")), MB_ICONWARNING | MB_OK) };
+                            }
+                        }
+                    }
+
                     match installer.pre_install()
                         .and_then(|_| installer.install())
                         .and_then(|_| installer.post_install())
                     {
                         Ok(_) => {
-                            unsafe { MessageBoxW(dialog, &HSTRING::from(t!("gui.msg_install_ok")), &HSTRING::from(t!("gui.title")), MB_ICONINFORMATION | MB_OK) };
+                            if let LoadLikelihood::No(reason) = installer.will_dll_load() {
+                                unsafe { MessageBoxW(dialog, &HSTRING::from(t!("gui.msg_install_ok_not_loaded", reason = reason)), &HSTRING::from(t!("gui.title")), MB_ICONWARNING | MB_OK) };
+                            } else {
+                                let message = format!("{}\n\n{}", t!("gui.msg_install_ok"), t!("gui.open_folder_prompt"));
+                                let res = unsafe { MessageBoxW(dialog, &HSTRING::from(message), &HSTRING::from(t!("gui.title")), MB_ICONINFORMATION | MB_YESNO) };
+                                if res == IDYES {
+                                    if let Some(dir) = installer.get_current_target_dir() {
+                                        utils::open_folder(&dir);
+                                    }
+                                }
+                            }
                         },
                         Err(e) => {
                             unsafe { MessageBoxW(dialog, &HSTRING::from(t!("gui.msg_install_fail", err = e.to_string())), &HSTRING::from(t!("gui.title")), MB_ICONERROR | MB_OK) };
@@ -538,6 +687,10 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                         return 0;
                     }
 
+                    if !confirm_launcher_closed(dialog) {
+                        return 0;
+                    }
+
                     let res = unsafe {
                         MessageBoxW(
                             dialog,
@@ -548,13 +701,26 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                     };
                     if res == IDOK {
                         let version_info_opt = installer.get_target_version_info(installer.target);
-                        if let Err(e) = installer.uninstall() {
-                            unsafe { MessageBoxW(dialog, &HSTRING::from(e.to_string()), &HSTRING::from(t!("gui.error")), MB_ICONERROR | MB_OK) };
-                            return 0;
+                        let follow_up = match installer.uninstall(UninstallOptions::default()) {
+                            Ok(follow_up) => follow_up,
+                            Err(e) => {
+                                unsafe { MessageBoxW(dialog, &HSTRING::from(e.to_string()), &HSTRING::from(t!("gui.error")), MB_ICONERROR | MB_OK) };
+                                return 0;
+                            }
+                        };
+                        let leftovers = installer.verify_uninstall();
+                        let (mut message, icon) = if leftovers.is_empty() {
+                            (t!("gui.uninstall_complete"), MB_ICONINFORMATION)
+                        } else {
+                            (t!("gui.uninstall_leftovers", list = leftovers.join("\n")), MB_ICONWARNING)
+                        };
+                        if let Some(follow_up_message) = follow_up.get_message() {
+                            message = format!("{}\n\n{}", message, follow_up_message);
                         }
+                        unsafe { MessageBoxW(dialog, &HSTRING::from(message), &HSTRING::from(t!("gui.uninstall")), icon | MB_OK) };
 
-                        refresh_all_target_labels(dialog);
-                        update_target(dialog, unsafe { GetDlgItem(dialog, IDC_TARGET).unwrap() }, installer.target as _);
+                        let install_dir = installer.install_dir().cloned().unwrap();
+                        clear_selection(dialog);
 
                         if let Some(version_info) = version_info_opt {
                             if !version_info.is_hachimi() {
@@ -563,7 +729,7 @@ unsafe extern "system" fn dlg_proc(dialog: HWND, message: u32, wparam: WPARAM, l
                             }
 
                             // Check if the hachimi data dir exists and prompt user to delete it
-                            let hachimi_dir = installer.install_dir().as_ref().unwrap().join("hachimi");
+                            let hachimi_dir = install_dir.join("hachimi");
                             let Ok(metadata) = std::fs::metadata(&hachimi_dir) else {
                                 return 0;
                             };
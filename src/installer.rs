@@ -1,45 +1,141 @@
-use std::{fs::File, io::{Write, Read}, path::{Path, PathBuf}};
+use std::{borrow::Cow, collections::HashMap, io::Read, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
 
-use pelite::resources::version_info::Language;
 use registry::Hive;
 use steamlocate::SteamDir;
 use tinyjson::JsonValue;
 use crate::i18n::t;
-use windows::{core::HSTRING, Win32::{Foundation::HWND, UI::{Shell::{FOLDERID_RoamingAppData, SHGetKnownFolderPath, KF_FLAG_DEFAULT}, WindowsAndMessaging::{MessageBoxW, IDOK, IDYES, MB_ICONINFORMATION, MB_ICONWARNING, MB_ICONQUESTION, MB_OK, MB_OKCANCEL, MB_YESNO}}}};
-
-use crate::utils::{self, get_system_directory};
-
-#[derive(Clone, Copy, Eq, PartialEq)]
+use windows::Win32::UI::Shell::{FOLDERID_LocalAppData, FOLDERID_ProgramFiles, FOLDERID_RoamingAppData, SHGetKnownFolderPath, KF_FLAG_DEFAULT};
+
+use crate::{payload::{self, PayloadOverride, SteamPatch}, prompt::{MessageBoxPrompter, Prompter}, utils::{self, get_system_directory}};
+
+const IFEO_KEY_PATH: &str = r"SOFTWARE\Microsoft\Windows NT\CurrentVersion\Image File Execution Options";
+const CURRENT_VERSION_KEY_PATH: &str = r"SOFTWARE\Microsoft\Windows NT\CurrentVersion";
+// DevOverrideEnable works since Windows Vista, but the installer only supports Windows 7 and later
+const MIN_DOTLOCAL_BUILD: u32 = 7600;
+const VDF_AUDIT_LOG_FILE_NAME: &str = "hachimi_vdf_audit.log";
+const AV_EXCLUSIONS_FILE_NAME: &str = "hachimi_av_exclusions.ps1";
+
+// DLLs shipped with the game or used by the installer itself, which a custom target must never replace
+const RESERVED_DLL_NAMES: &[&str] = &[
+    "GameAssembly.dll",
+    "baselib.dll",
+    "cri_ware_unity.dll",
+    "libnative.dll",
+    "apphelp.dll"
+];
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum GameVersion {
     DMM,
     Steam,
     SteamGlobal
 }
 
+impl GameVersion {
+    pub const VALUES: &[Self] = &[
+        Self::DMM,
+        Self::Steam,
+        Self::SteamGlobal
+    ];
+
+    pub fn exe_name(&self) -> &'static str {
+        match self {
+            Self::DMM => "umamusume.exe",
+            Self::Steam => "UmamusumePrettyDerby_Jpn.exe",
+            Self::SteamGlobal => "UmamusumePrettyDerby.exe"
+        }
+    }
+
+    pub fn steam_app_id(&self) -> Option<u32> {
+        match self {
+            Self::DMM => None,
+            Self::Steam => Some(3564400),
+            Self::SteamGlobal => Some(3224770)
+        }
+    }
+
+    pub fn display_name(&self) -> String {
+        match self {
+            Self::DMM => "DMM".to_owned(),
+            Self::Steam => t!("gui.steam_jp"),
+            Self::SteamGlobal => t!("gui.steam_global")
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::DMM => "dmm",
+            Self::Steam => "steam",
+            Self::SteamGlobal => "steam-global"
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<GameVersion> {
+        match id {
+            "dmm" => Some(Self::DMM),
+            "steam" => Some(Self::Steam),
+            "steam-global" => Some(Self::SteamGlobal),
+            _ => None
+        }
+    }
+}
+
+pub type ProgressCallback = Box<dyn Fn(&str, u8)>;
+
 pub struct Installer {
     dmm_install_dir: Option<PathBuf>,
     steam_install_dir: Option<PathBuf>,
     steam_global_install_dir: Option<PathBuf>,
     install_dir: Option<PathBuf>,
     game_version: Option<GameVersion>,
+    // Located once per detection run, None if Steam isn't installed
+    steam_dir: Option<SteamDir>,
+    // Used instead of locating Steam, e.g. for portable installs that aren't in the registry
+    steam_root: Option<PathBuf>,
 
     pub target: Target,
     pub custom_target: Option<String>,
+    // Allows a custom target to replace an existing DLL that isn't Hachimi
+    pub force_custom_target: bool,
+    // Install these files instead of the bundled DLLs
+    pub dll_override: Option<PayloadOverride>,
+    pub cellar_override: Option<PayloadOverride>,
+    // Executable names used by test server builds, checked after the retail names
+    pub beta_exes: Vec<(GameVersion, String)>,
+    // Files and dirs relative to the install dir that are carried over by migrate
+    pub preserved_data: Vec<PathBuf>,
+    // Used for the app manifest instead of the detected version's app id, e.g. for test branches
+    pub steam_app_id: Option<u32>,
+    // Where files are written before being moved into place. Next to them if not set,
+    // which keeps the move atomic since it's on the same volume.
+    pub temp_dir: Option<PathBuf>,
+    pub steam_strategy: SteamStrategy,
+    // Enables DotLocal DLL redirection without asking when it's needed. If it stays disabled,
+    // a DotLocal install won't be loaded by the game.
+    pub auto_enable_dll_redirection: bool,
+    // Leaves the Steam (Japan) executable's backup in place after uninstall restores from it,
+    // so a later install can verify the original right away
+    pub keep_exe_backup: bool,
+    // Appends every change to Steam's app manifest to the audit log, see vdf_audit_log_path()
+    pub vdf_audit_log: bool,
     system_dir: PathBuf,
-    pub hwnd: Option<HWND>
+    // Set from another thread to stop at the next checkpoint, see install()
+    pub cancel: Arc<AtomicBool>,
+    // Called with the current stage and its percentage during long operations
+    pub progress: Option<ProgressCallback>,
+    pub prompter: Box<dyn Prompter>
 }
 
 impl Installer {
-    fn detect_version_from_dir(dir: &Path) -> Option<GameVersion> {
-        if dir.join("umamusume.exe").is_file() {
-            Some(GameVersion::DMM)
-        } else if dir.join("UmamusumePrettyDerby_Jpn.exe").is_file() {
-            Some(GameVersion::Steam)
-        } else if dir.join("UmamusumePrettyDerby.exe").is_file() {
-            Some(GameVersion::SteamGlobal)
-        } else {
-            None
-        }
+    fn find_game_exe(&self, dir: &Path) -> Option<(GameVersion, String)> {
+        GameVersion::VALUES.iter()
+            .map(|version| (*version, version.exe_name().to_owned()))
+            .chain(self.beta_exes.iter().cloned())
+            .find(|(_, exe_name)| dir.join(exe_name).is_file())
+    }
+
+    fn detect_version_from_dir(&self, dir: &Path) -> Option<GameVersion> {
+        self.find_game_exe(dir).map(|(version, _)| version)
     }
 
     pub fn new(target: Target, custom_target: Option<String>) -> Installer {
@@ -49,29 +145,67 @@ impl Installer {
             steam_global_install_dir: None,
             install_dir: None,
             game_version: None,
+            steam_dir: None,
+            steam_root: None,
             target,
             custom_target,
+            force_custom_target: false,
+            dll_override: None,
+            cellar_override: None,
+            beta_exes: Vec::new(),
+            preserved_data: vec![PathBuf::from("hachimi")],
+            steam_app_id: None,
+            temp_dir: None,
+            steam_strategy: SteamStrategy::default(),
+            auto_enable_dll_redirection: false,
+            keep_exe_backup: false,
+            vdf_audit_log: false,
             system_dir: get_system_directory(),
-            hwnd: None
+            cancel: Arc::new(AtomicBool::new(false)),
+            progress: None,
+            prompter: Box::new(MessageBoxPrompter::new(None))
         }
     }
 
+    /// Finds the game folder from a near miss, such as its Data folder or the folder containing it.
+    /// Returns `dir` itself if it already is one.
+    pub fn find_game_root(&self, dir: &Path) -> Option<PathBuf> {
+        if let Some(root) = dir.ancestors().find(|dir| self.detect_version_from_dir(dir).is_some()) {
+            return Some(root.to_path_buf());
+        }
+        std::fs::read_dir(dir).ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.is_dir() && self.detect_version_from_dir(path).is_some())
+    }
+
+    /// Only replaces the detected dir for the version found in `dir`, the others are kept.
     pub fn set_install_dir(&mut self, dir: PathBuf) -> Result<(), Error> {
-        match Self::detect_version_from_dir(&dir) {
+        match self.detect_version_from_dir(&dir) {
             Some(version) => {
                 self.install_dir = Some(dir.clone());
                 self.game_version = Some(version);
-                match version {
-                    GameVersion::DMM => self.dmm_install_dir = Some(dir),
-                    GameVersion::Steam => self.steam_install_dir = Some(dir),
-                    GameVersion::SteamGlobal => self.steam_global_install_dir = Some(dir),
-                }
+                self.set_detected_dir(version, dir);
                 Ok(())
             }
             None => Err(Error::InvalidInstallDir)
         }
     }
 
+    fn set_detected_dir(&mut self, version: GameVersion, dir: PathBuf) {
+        match version {
+            GameVersion::DMM => self.dmm_install_dir = Some(dir),
+            GameVersion::Steam => self.steam_install_dir = Some(dir),
+            GameVersion::SteamGlobal => self.steam_global_install_dir = Some(dir),
+        }
+    }
+
+    /// Deselects the install dir and game version. The detected dirs for each version are kept.
+    pub fn clear_selection(&mut self) {
+        self.install_dir = None;
+        self.game_version = None;
+    }
+
     pub fn install_dir(&self) -> Option<&PathBuf> {
         self.install_dir.as_ref()
     }
@@ -80,23 +214,43 @@ impl Installer {
         self.game_version
     }
 
+    /// Uses `dir` as the Steam folder instead of the one Steam registered, for the next detection.
+    pub fn set_steam_dir(&mut self, dir: PathBuf) -> Result<(), Error> {
+        if !dir.join("steamapps").is_dir() && !dir.join("userdata").is_dir() {
+            return Err(Error::InvalidSteamDir(dir));
+        }
+        self.steam_root = Some(dir);
+        Ok(())
+    }
+
+    fn locate_steam_dir(&self) -> Option<SteamDir> {
+        match &self.steam_root {
+            Some(dir) => SteamDir::from_dir(dir).ok(),
+            None => SteamDir::locate().ok()
+        }
+    }
+
     pub fn detect_install_dir(&mut self) {
+        self.steam_dir = self.locate_steam_dir();
+
         if let Some(dmm_dir) = Self::detect_dmm_install_dir() {
             self.install_dir = Some(dmm_dir);
             self.game_version = Some(GameVersion::DMM);
-        } else if let Some(steam_dir) = Self::detect_steam_install_dir() {
+        } else if let Some(steam_dir) = Self::detect_steam_install_dir(self.steam_dir.as_ref()) {
             self.install_dir = Some(steam_dir);
             self.game_version = Some(GameVersion::Steam);
-        } else if let Some(steam_global_dir) = Self::detect_steam_global_install_dir() {
+        } else if let Some(steam_global_dir) = Self::detect_steam_global_install_dir(self.steam_dir.as_ref()) {
             self.install_dir = Some(steam_global_dir);
             self.game_version = Some(GameVersion::SteamGlobal);
         }
     }
 
     pub fn detect_install_dirs(&mut self) {
+        self.steam_dir = self.locate_steam_dir();
+
         self.dmm_install_dir = Self::detect_dmm_install_dir();
-        self.steam_install_dir = Self::detect_steam_install_dir();
-        self.steam_global_install_dir = Self::detect_steam_global_install_dir();
+        self.steam_install_dir = Self::detect_steam_install_dir(self.steam_dir.as_ref());
+        self.steam_global_install_dir = Self::detect_steam_global_install_dir(self.steam_dir.as_ref());
 
         if self.install_dir.is_none() {
             if self.dmm_install_dir.is_some() {
@@ -121,6 +275,34 @@ impl Installer {
         self.steam_global_install_dir.as_ref()
     }
 
+    /// Where the folder picker should start: the selected or a detected game folder, then the
+    /// Steam library the game is usually installed to, then Program Files.
+    pub fn suggested_browse_start(&self) -> Option<PathBuf> {
+        let detected_dir = [
+            self.install_dir.as_ref(),
+            self.dmm_install_dir.as_ref(),
+            self.steam_install_dir.as_ref(),
+            self.steam_global_install_dir.as_ref()
+        ]
+            .into_iter()
+            .flatten()
+            .find(|dir| dir.is_dir());
+        if let Some(dir) = detected_dir {
+            return Some(dir.clone());
+        }
+
+        if let Some(steam_dir) = &self.steam_dir {
+            let common_dir = steam_dir.path().join("steamapps").join("common");
+            if common_dir.is_dir() {
+                return Some(common_dir);
+            }
+        }
+
+        let program_files_wstr = unsafe { SHGetKnownFolderPath(&FOLDERID_ProgramFiles, KF_FLAG_DEFAULT, None).ok()? };
+        let program_files_str = unsafe { program_files_wstr.to_string().ok()? };
+        Some(PathBuf::from(program_files_str))
+    }
+
     pub fn set_game_version(&mut self, version: GameVersion) -> Option<&PathBuf> {
         self.game_version = Some(version);
         match version {
@@ -131,6 +313,35 @@ impl Installer {
         self.install_dir.as_ref()
     }
 
+    /// Returns the game list from dmmgame.cnf. If the file isn't valid JSON as a whole,
+    /// only the "contents" array is parsed.
+    fn parse_dmm_config_contents(config_str: &str) -> Option<Vec<JsonValue>> {
+        let config_str = config_str.trim_start_matches('\u{feff}').trim();
+        match config_str.parse() {
+            Ok(JsonValue::Object(mut config)) => {
+                let Some(JsonValue::Array(contents)) = config.remove("contents") else {
+                    utils::log("dmmgame.cnf has no contents array");
+                    return None;
+                };
+                return Some(contents);
+            },
+            Ok(_) => return None,
+            Err(e) => utils::log(&format!("Failed to parse dmmgame.cnf, looking for its contents array instead: {}", e))
+        }
+
+        let key_start = config_str.find("\"contents\"")?;
+        let array_start = key_start + config_str[key_start..].find('[')?;
+        let array_len = utils::find_json_array_len(&config_str[array_start..])?;
+        match config_str[array_start..array_start + array_len].parse() {
+            Ok(JsonValue::Array(contents)) => Some(contents),
+            Ok(_) => None,
+            Err(e) => {
+                utils::log(&format!("Failed to parse the contents array of dmmgame.cnf: {}", e));
+                None
+            }
+        }
+    }
+
     fn detect_dmm_install_dir() -> Option<PathBuf> {
         let app_data_dir_wstr = unsafe { SHGetKnownFolderPath(&FOLDERID_RoamingAppData, KF_FLAG_DEFAULT, None).ok()? };
         let app_data_dir_str = unsafe { app_data_dir_wstr.to_string().ok()? };
@@ -139,28 +350,23 @@ impl Installer {
         dmm_config_path.push("dmmgame.cnf");
 
         let config_str = std::fs::read_to_string(dmm_config_path).ok()?;
-        let JsonValue::Object(config) = config_str.parse().ok()? else {
-            return None;
-        };
-        let JsonValue::Array(config_contents) = &config["contents"] else {
-            return None;
-        };
-        for value in config_contents {
+        let config_contents = Self::parse_dmm_config_contents(&config_str)?;
+        for value in &config_contents {
             let JsonValue::Object(game) = value else {
                 return None;
             };
 
-            let JsonValue::String(product_id) = &game["productId"] else {
+            let Some(JsonValue::String(product_id)) = game.get("productId") else {
                 continue;
             };
             if product_id != "umamusume" {
                 continue;
             }
 
-            let JsonValue::Object(detail) = &game["detail"] else {
+            let Some(JsonValue::Object(detail)) = game.get("detail") else {
                 return None;
             };
-            let JsonValue::String(path_str) = &detail["path"] else {
+            let Some(JsonValue::String(path_str)) = detail.get("path") else {
                 return None;
             };
 
@@ -176,53 +382,59 @@ impl Installer {
         None
     }
 
-    fn detect_steam_install_dir() -> Option<PathBuf> {
-        const STEAM_APP_ID: u32 = 3564400;
-        const GAME_EXE_NAME: &str = "UmamusumePrettyDerby_Jpn.exe";
+    /// Finds a folder in a library's steamapps/common folder that contains `exe_name`.
+    fn find_exe_in_steam_common_dir(library_path: &Path, exe_name: &str) -> Option<PathBuf> {
+        std::fs::read_dir(library_path.join("steamapps").join("common")).ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.join(exe_name).is_file())
+    }
 
-        if let Ok(steamdir) = SteamDir::locate() {
-            if let Ok(Some((app, library))) = steamdir.find_app(STEAM_APP_ID) {
+    fn detect_steam_app_install_dir(steam_dir: Option<&SteamDir>, app_id: u32, exe_name: &str) -> Option<PathBuf> {
+        let steam_dir = steam_dir?;
+        let Some((app, library)) = steam_dir.find_app(app_id).ok().flatten() else {
+            // Games borrowed through family sharing aren't always reported for the current account,
+            // but their files are still in one of the libraries
+            let game_path = steam_dir.libraries().ok()?
+                .filter_map(|library| library.ok())
+                .find_map(|library| Self::find_exe_in_steam_common_dir(library.path(), exe_name))?;
+            utils::log(&format!("App {} not found in any Steam library, using {} instead", app_id, game_path.display()));
+            return Some(game_path);
+        };
 
-                let game_path = library.path()
-                    .join("steamapps")
-                    .join("common")
-                    .join(&app.install_dir);
+        let game_path = library.path()
+            .join("steamapps")
+            .join("common")
+            .join(&app.install_dir);
 
-                if game_path.join(GAME_EXE_NAME).is_file() {
-                    return Some(game_path);
-                }
-            }
+        if game_path.join(exe_name).is_file() {
+            return Some(game_path);
         }
 
-        None
+        // The install dir in the app manifest can be stale, e.g. after the folder was renamed
+        let game_path = Self::find_exe_in_steam_common_dir(library.path(), exe_name)?;
+        utils::log(&format!("{} not found in {}, using {} instead", exe_name, app.install_dir, game_path.display()));
+        Some(game_path)
     }
 
-    fn detect_steam_global_install_dir() -> Option<PathBuf> {
-        const STEAM_APP_ID: u32 = 3224770;
-        const GAME_EXE_NAME: &str = "UmamusumePrettyDerby.exe";
-
-        if let Ok(steamdir) = SteamDir::locate() {
-            if let Ok(Some((app, library))) = steamdir.find_app(STEAM_APP_ID) {
-
-                let game_path = library.path()
-                    .join("steamapps")
-                    .join("common")
-                    .join(&app.install_dir);
-
-                if game_path.join(GAME_EXE_NAME).is_file() {
-                    return Some(game_path);
-                }
-            }
-        }
+    fn detect_steam_install_dir(steam_dir: Option<&SteamDir>) -> Option<PathBuf> {
+        let version = GameVersion::Steam;
+        Self::detect_steam_app_install_dir(steam_dir, version.steam_app_id()?, version.exe_name())
+    }
 
-        None
+    fn detect_steam_global_install_dir(steam_dir: Option<&SteamDir>) -> Option<PathBuf> {
+        let version = GameVersion::SteamGlobal;
+        Self::detect_steam_app_install_dir(steam_dir, version.steam_app_id()?, version.exe_name())
     }
 
-    fn get_install_method(&self, target: Target) -> InstallMethod {
+    fn get_install_method_for(&self, version: Option<GameVersion>, target: Target) -> InstallMethod {
+        if version == Some(GameVersion::Steam) && self.steam_strategy == SteamStrategy::DotLocalProxy {
+            return InstallMethod::DotLocal;
+        }
         match target {
             Target::UnityPlayer => InstallMethod::DotLocal,
             Target::CriManaVpx => {
-                if self.game_version == Some(GameVersion::Steam) || self.game_version == Some(GameVersion::SteamGlobal) {
+                if version == Some(GameVersion::Steam) || version == Some(GameVersion::SteamGlobal) {
                     InstallMethod::Direct
                 } else {
                     InstallMethod::PluginShim
@@ -231,21 +443,34 @@ impl Installer {
         }
     }
 
-    fn get_target_path_internal(&self, target: Target, p: impl AsRef<Path>) -> Option<PathBuf> {
-        let install_dir = self.install_dir.as_ref()?;
-        Some(match self.get_install_method(target) {
+    pub fn get_install_method(&self, target: Target) -> InstallMethod {
+        self.get_install_method_for(self.game_version, target)
+    }
+
+    /// Whether installing patches the Steam (Japan) executable.
+    fn patches_steam_exe(&self) -> bool {
+        self.game_version == Some(GameVersion::Steam) && self.steam_strategy == SteamStrategy::ExePatch
+    }
+
+    fn get_target_path_in(&self, install_dir: &Path, version: Option<GameVersion>, target: Target, p: impl AsRef<Path>) -> PathBuf {
+        self.get_target_path_for_method(install_dir, version, self.get_install_method_for(version, target), p)
+    }
+
+    fn get_target_path_for_method(&self, install_dir: &Path, version: Option<GameVersion>, method: InstallMethod, p: impl AsRef<Path>) -> PathBuf {
+        match method {
             InstallMethod::DotLocal => {
-                let exe_name = match self.game_version {
-                    Some(GameVersion::Steam) => "UmamusumePrettyDerby_Jpn.exe",
-                    Some(GameVersion::SteamGlobal) => "UmamusumePrettyDerby.exe",
-                    Some(GameVersion::DMM) | _ => "umamusume.exe",
-                };
+                let exe_name = self.game_exe_name_in(install_dir, version.unwrap_or(GameVersion::DMM));
                 let local_folder_name = format!("{}.local", exe_name);
                 install_dir.join(local_folder_name).join(p)
             }
             InstallMethod::PluginShim => self.system_dir.join(p),
             InstallMethod::Direct => install_dir.join(p),
-        })
+        }
+    }
+
+    fn get_target_path_internal(&self, target: Target, p: impl AsRef<Path>) -> Option<PathBuf> {
+        let install_dir = self.install_dir.as_ref()?;
+        Some(self.get_target_path_in(install_dir, self.game_version, target, p))
     }
 
     pub fn get_target_path(&self, target: Target) -> Option<PathBuf> {
@@ -261,22 +486,93 @@ impl Installer {
         })
     }
 
-    const LANG_NEUTRAL_UNICODE: Language = Language { lang_id: 0x0000, charset_id: 0x04b0 };
+    fn game_exe_name_in(&self, install_dir: &Path, version: GameVersion) -> String {
+        if !install_dir.join(version.exe_name()).is_file() {
+            let beta_exe = self.beta_exes.iter()
+                .find(|(v, exe_name)| *v == version && install_dir.join(exe_name).is_file());
+            if let Some((_, exe_name)) = beta_exe {
+                return exe_name.clone();
+            }
+        }
+        version.exe_name().to_owned()
+    }
+
+    /// The game executable in the install dir. This is a beta exe name only if the retail one is absent.
+    pub fn game_exe_name(&self) -> String {
+        let version = self.game_version.unwrap_or(GameVersion::DMM);
+        match &self.install_dir {
+            Some(install_dir) => self.game_exe_name_in(install_dir, version),
+            None => version.exe_name().to_owned()
+        }
+    }
+
+    pub fn steam_app_id(&self) -> Option<u32> {
+        self.steam_app_id.or_else(|| self.game_version?.steam_app_id())
+    }
+
+    pub fn is_beta_build(&self) -> bool {
+        self.game_version.is_some_and(|version| self.game_exe_name() != version.exe_name())
+    }
+
+    fn read_architecture(path: &Path) -> Option<Architecture> {
+        let map = pelite::FileMap::open(utils::extended_length_path(path)).ok()?;
+        Architecture::from_machine(utils::read_pe_machine(map.as_ref())?)
+    }
+
+    pub fn game_architecture(&self) -> Option<Architecture> {
+        Self::read_architecture(&self.install_dir.as_ref()?.join(self.game_exe_name()))
+    }
+
+    fn check_architecture(&self, dll_data: &[u8]) -> Result<(), Error> {
+        let Some(game_arch) = self.game_architecture() else {
+            return Ok(());
+        };
+        let Some(dll_arch) = utils::read_pe_machine(dll_data).and_then(Architecture::from_machine) else {
+            return Ok(());
+        };
+        if dll_arch != game_arch {
+            return Err(Error::ArchitectureMismatch(dll_arch, game_arch));
+        }
+        Ok(())
+    }
+
+    pub fn get_game_version_info(&self) -> Option<TargetVersionInfo> {
+        Self::read_version_info(&self.install_dir.as_ref()?.join(self.game_exe_name()))
+    }
+
     pub fn get_target_version_info(&self, target: Target) -> Option<TargetVersionInfo> {
-        let path = self.get_target_path(target)?;
-        let map = pelite::FileMap::open(&path).ok()?;
+        Self::read_version_info(&self.get_target_path(target)?)
+    }
+
+    pub fn get_target_status(&self, target: Target) -> TargetStatus {
+        match self.get_target_path(target) {
+            Some(path) => Self::read_target_status(&path),
+            None => TargetStatus::Absent
+        }
+    }
 
-        // File exists, so return empty version info if we can't read it
+    fn read_target_status(path: &Path) -> TargetStatus {
+        if !path.is_file() {
+            return TargetStatus::Absent;
+        }
+        let map = match pelite::FileMap::open(utils::extended_length_path(path)) {
+            Ok(map) => map,
+            Err(e) => return TargetStatus::Unreadable(e.to_string())
+        };
         let Some(version_info) = utils::read_pe_version_info(map.as_ref()) else {
-            return Some(TargetVersionInfo::default());
+            return TargetStatus::Unreadable(t!("installer.no_version_info"));
         };
 
-        Some(TargetVersionInfo {
-            name: version_info.value(Self::LANG_NEUTRAL_UNICODE, "ProductName"),
-            version: version_info.value(Self::LANG_NEUTRAL_UNICODE, "ProductVersion")
+        TargetStatus::Present(TargetVersionInfo {
+            name: utils::read_version_string(version_info, "ProductName"),
+            version: utils::read_version_string(version_info, "ProductVersion")
         })
     }
 
+    fn read_version_info(path: &Path) -> Option<TargetVersionInfo> {
+        Self::read_target_status(path).into_version_info()
+    }
+
     pub fn get_target_display_label(&self, target: Target) -> String {
         if let Some(version_info) = self.get_target_version_info(target) {
             version_info.get_display_label(target)
@@ -286,28 +582,246 @@ impl Installer {
         }
     }
 
+    /// The folder the target DLL is installed to, e.g. the .local folder for DotLocal installs.
+    pub fn get_current_target_dir(&self) -> Option<PathBuf> {
+        Some(self.get_current_target_path()?.parent()?.to_path_buf())
+    }
+
     pub fn is_current_target_installed(&self) -> bool {
-        let Some(path) = self.get_current_target_path() else {
+        self.get_current_target_path()
+            .is_some_and(|path| Self::read_target_status(&path).is_present())
+    }
+
+    pub fn get_hachimi_installed_target(&self, scope: TargetScope) -> Option<Target> {
+        scope.targets().iter()
+            .find(|target| matches!(self.get_target_status(**target), TargetStatus::Present(info) if info.is_hachimi()))
+            .copied()
+    }
+
+    /// Infers how Hachimi was installed to `dir` from the files there, regardless of the current
+    /// target and version, so that files left by a different method can be recognized.
+    pub fn detect_existing_install_shape(&self, dir: &Path, scope: TargetScope) -> InstallShape {
+        let version = self.detect_version_from_dir(dir);
+        let dll_names: Vec<&str> = scope.targets().iter()
+            .map(|target| target.dll_name())
+            .chain(self.custom_target.as_deref())
+            .collect();
+
+        let methods: Vec<InstallMethod> = InstallMethod::VALUES.iter()
+            .filter(|method| dll_names.iter().any(|dll_name| {
+                let path = self.get_target_path_for_method(dir, version, **method, dll_name);
+                Self::read_version_info(&path).is_some_and(|info| info.is_hachimi())
+            }))
+            .copied()
+            .collect();
+
+        match methods[..] {
+            [] => InstallShape::NotInstalled,
+            [method] => {
+                let exe_patched = version == Some(GameVersion::Steam) &&
+                    dir.join(GameVersion::Steam.exe_name()).with_extension("exe.bak").is_file();
+                InstallShape::Installed { method, exe_patched }
+            },
+            _ => InstallShape::Mixed(methods)
+        }
+    }
+
+    fn is_dotlocal_enabled() -> bool {
+        let Ok(regkey) = Hive::LocalMachine.open(IFEO_KEY_PATH, registry::Security::Read) else {
             return false;
         };
-        let Ok(metadata) = std::fs::metadata(&path) else {
+        matches!(regkey.value("DevOverrideEnable"), Ok(registry::Data::U32(v)) if v != 0)
+    }
+
+    /// The Windows build number, e.g. 19045. Read from the registry, since GetVersionEx reports
+    /// an older version to programs that aren't manifested for the running one.
+    fn get_windows_build() -> Option<u32> {
+        let regkey = Hive::LocalMachine.open(CURRENT_VERSION_KEY_PATH, registry::Security::Read).ok()?;
+        match regkey.value("CurrentBuildNumber").ok()? {
+            registry::Data::String(build) => build.to_string_lossy().trim().parse().ok(),
+            _ => None
+        }
+    }
+
+    /// Whether this system can redirect DLL loads to a .local folder with DevOverrideEnable,
+    /// which DotLocal installs depend on. Builds that can't be read are assumed to support it.
+    pub fn supports_dotlocal_redirection() -> bool {
+        // Without the IFEO key there's nowhere to enable it
+        if Hive::LocalMachine.open(IFEO_KEY_PATH, registry::Security::Read).is_err() {
             return false;
+        }
+        Self::get_windows_build().is_none_or(|build| build >= MIN_DOTLOCAL_BUILD)
+    }
+
+    /// The install method for `target`, and whether it works on this system. When it needs DotLocal
+    /// redirection that isn't supported, also recommends a target that's installed without it.
+    pub fn effective_install_method(&self, target: Target) -> EffectiveInstallMethod {
+        let method = self.get_install_method(target);
+        let supported = method != InstallMethod::DotLocal || Self::supports_dotlocal_redirection();
+        let recommended_target = if supported {
+            None
+        } else {
+            Target::VALUES.iter()
+                .find(|target| self.get_install_method(**target) != InstallMethod::DotLocal)
+                .copied()
+        };
+        EffectiveInstallMethod { method, supported, recommended_target }
+    }
+
+    /// Whether the game will load the current target the next time it starts.
+    pub fn will_dll_load(&self) -> LoadLikelihood {
+        let Some(install_dir) = &self.install_dir else {
+            return LoadLikelihood::No(t!("error.no_install_dir"));
         };
-        metadata.is_file()
+        if !self.is_current_target_installed() {
+            return LoadLikelihood::No(t!("installer.load_reason.not_installed"));
+        }
+
+        match self.get_install_method(self.target) {
+            InstallMethod::DotLocal => {
+                if !Self::supports_dotlocal_redirection() {
+                    return LoadLikelihood::No(t!("installer.load_reason.dotlocal_unsupported"));
+                }
+                let exe_name = self.game_exe_name();
+                if !install_dir.join(&exe_name).is_file() {
+                    return LoadLikelihood::No(t!("installer.load_reason.exe_missing", exe_name = exe_name));
+                }
+                if !Self::is_dotlocal_enabled() {
+                    return LoadLikelihood::No(t!("installer.load_reason.dotlocal_disabled"));
+                }
+                // DevOverrideEnable is only read at boot, so assume any change to the key since then is still pending
+                if utils::is_hklm_key_modified_since_boot(IFEO_KEY_PATH) {
+                    return LoadLikelihood::AfterReboot;
+                }
+            },
+            InstallMethod::PluginShim => {
+                if !self.get_dest_plugin_path().is_some_and(|path| path.is_file()) {
+                    return LoadLikelihood::No(t!("installer.load_reason.original_plugin_missing"));
+                }
+            },
+            InstallMethod::Direct => {}
+        }
+
+        LoadLikelihood::Yes
+    }
+
+    /// Checks the current install without changing anything, and returns what's wrong with it.
+    /// Unlike `will_dll_load`, keeps going after the first problem. Hashes the whole Steam
+    /// (Japan) executable when it's patched, so it takes a moment.
+    pub fn verify_install(&self) -> Result<Vec<String>, Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+        let target_path = self.get_current_target_path().ok_or(Error::NoInstallDir)?;
+        let mut issues = Vec::new();
+
+        match Self::read_target_status(&target_path) {
+            TargetStatus::Absent => issues.push(t!("installer.load_reason.not_installed")),
+            TargetStatus::Present(info) if info.is_hachimi() => {},
+            _ => issues.push(t!("installer.verify.not_hachimi", path = target_path.display()))
+        }
+
+        match self.get_install_method(self.target) {
+            InstallMethod::DotLocal => {
+                if !self.get_cellar_path().is_some_and(|path| path.is_file()) {
+                    issues.push(t!("installer.verify.cellar_missing"));
+                }
+                if !Self::is_dotlocal_enabled() {
+                    issues.push(t!("installer.load_reason.dotlocal_disabled"));
+                }
+            },
+            InstallMethod::PluginShim => {
+                if !self.get_dest_plugin_path().is_some_and(|path| path.is_file()) {
+                    issues.push(t!("installer.load_reason.original_plugin_missing"));
+                }
+            },
+            InstallMethod::Direct => {}
+        }
+
+        if self.patches_steam_exe() {
+            let exe_path = Self::steam_exe_path(install_dir);
+            let patches = payload::steam_patches()?;
+            match utils::hash_file(&exe_path, &self.cancel, &mut |_, _| {}) {
+                Ok(hash) if patches.iter().any(|patch| patch.patched_hash == hash) => {},
+                Ok(_) => issues.push(t!("installer.verify.exe_not_patched", exe_name = GameVersion::Steam.exe_name())),
+                Err(e) => issues.push(e)
+            }
+            self.check_cancelled()?;
+        }
+
+        Ok(issues)
+    }
+
+    /// Every detected install dir, along with the Hachimi DLL installed there if any.
+    pub fn candidate_dirs(&self) -> Vec<CandidateDir> {
+        let dirs = [
+            (GameVersion::DMM, self.dmm_install_dir()),
+            (GameVersion::Steam, self.steam_install_dir()),
+            (GameVersion::SteamGlobal, self.steam_global_install_dir())
+        ];
+        dirs.into_iter()
+            .filter_map(|(version, dir)| Some((version, dir?)))
+            .map(|(version, dir)| {
+                let hachimi = Target::VALUES.iter()
+                    .filter_map(|target| {
+                        let path = self.get_target_path_in(dir, Some(version), *target, target.dll_name());
+                        Self::read_version_info(&path)
+                    })
+                    .find(|version_info| version_info.is_hachimi());
+                let architecture = Self::read_architecture(&dir.join(self.game_exe_name_in(dir, version)));
+                CandidateDir { version, path: dir.clone(), architecture, hachimi }
+            })
+            .collect()
     }
 
-    pub fn get_hachimi_installed_target(&self) -> Option<Target> {
+    /// Finds non-Hachimi DLLs occupying any of the proxy slots, such as other mods.
+    pub fn detect_conflicts(&self) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
         for target in Target::VALUES {
-            if let Some(version_info) = self.get_target_version_info(*target) {
-                if version_info.is_hachimi() {
-                    return Some(*target);
+            let Some(path) = self.get_target_path(*target) else {
+                continue;
+            };
+            let Some(version_info) = self.get_target_version_info(*target) else {
+                continue;
+            };
+            if !version_info.is_hachimi() {
+                conflicts.push(Conflict { target: *target, path, version_info });
+                continue;
+            }
+
+            // Once the shim is installed, the game's plugin folder should be empty. A DLL put
+            // back there is loaded instead of the shim.
+            if self.get_install_method(*target) != InstallMethod::PluginShim {
+                continue;
+            }
+            let Some(plugin_path) = self.get_src_plugin_path_for(*target) else {
+                continue;
+            };
+            if let Some(version_info) = Self::read_version_info(&plugin_path) {
+                if !version_info.is_hachimi() {
+                    conflicts.push(Conflict { target: *target, path: plugin_path, version_info });
                 }
             }
         }
-        None
+        conflicts
+    }
+
+    fn report_progress(&self, stage: &str, done: u64, total: u64) {
+        if let Some(progress) = &self.progress {
+            let percent = if total == 0 { 100 } else { (done * 100 / total).min(100) as u8 };
+            progress(stage, percent);
+        }
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        if self.cancel.load(Ordering::Relaxed) {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn pre_install(&self) -> Result<(), Error> {
+        self.check_cancelled()?;
+
         if self.get_install_method(self.target) == InstallMethod::PluginShim {
             let dest_dll = self.get_dest_plugin_path().ok_or(Error::NoInstallDir)?;
             let src_dll = self.get_src_plugin_path().ok_or(Error::NoInstallDir)?;
@@ -320,58 +834,44 @@ impl Installer {
     }
 
     fn check_and_prompt_steam_autoupdate(&self) -> Result<(), Error> {
-        if self.hwnd.is_none() {
+        if !self.prompter.wants_optional_prompts() {
             return Ok(());
         }
 
-        let (steam_app_id_str, install_dir) = match self.game_version {
-            Some(GameVersion::Steam) => ("3564400", self.install_dir.as_ref()),
-            _ => return Ok(()),
+        let Some(steam_app_id) = self.steam_app_id() else {
+            return Ok(());
         };
+        if let Some(manifest_path) = self.steam_manifest_path() {
+            let backup_path = manifest_path.with_extension("acf.bak");
 
-        if let Some(install_dir) = install_dir {
-            if let Some(steamapps_path) = find_steamapps_folder(install_dir) {
-                let manifest_path = steamapps_path.join(format!("appmanifest_{}.acf", steam_app_id_str));
-                let backup_path = manifest_path.with_extension("acf.bak");
+            if !manifest_path.is_file() {
+                return Ok(());
+            }
 
-                if !manifest_path.is_file() {
-                    return Ok(());
-                }
+            let Ok(content) = std::fs::read_to_string(&manifest_path) else { return Ok(()) };
 
-                let Ok(content) = std::fs::read_to_string(&manifest_path) else { return Ok(()) };
+            if content.contains("\"AutoUpdateBehavior\"\t\t\"1\"") {
+                return Ok(());
+            }
 
-                if content.contains("\"AutoUpdateBehavior\"\t\t\"1\"") {
-                    return Ok(());
-                }
+            if backup_path.exists() {
+                return Ok(());
+            }
 
-                if backup_path.exists() {
-                    return Ok(());
+            if self.prompter.confirm(
+                &t!("installer.change_auto_update_setting"),
+                &t!("installer.steam_auto_update_recommendation_prompt")
+            ) {
+                if !backup_path.exists() {
+                    std::fs::copy(&manifest_path, &backup_path)?;
                 }
-
-                let res = unsafe {
-                    MessageBoxW(
-                        self.hwnd.as_ref(),
-                        &HSTRING::from(t!("installer.steam_auto_update_recommendation_prompt")),
-                        &HSTRING::from(t!("installer.change_auto_update_setting")),
-                        MB_ICONQUESTION | MB_YESNO
-                    )
-                };
-
-                if res == IDYES {
-                    if !backup_path.exists() {
-                        std::fs::copy(&manifest_path, &backup_path)?;
-                    }
-                    let new_content = content.replace("\"AutoUpdateBehavior\"\t\t\"0\"", "\"AutoUpdateBehavior\"\t\t\"1\"");
-                    if std::fs::write(&manifest_path, new_content).is_ok() {
-                        unsafe {
-                            MessageBoxW(
-                                self.hwnd.as_ref(),
-                                &HSTRING::from(t!("installer.steam_auto_update_success_message")),
-                                &HSTRING::from(t!("installer.auto_update_setting_changed")),
-                                MB_ICONINFORMATION | MB_OK
-                            );
-                        }
-                    }
+                let new_content = content.replace("\"AutoUpdateBehavior\"\t\t\"0\"", "\"AutoUpdateBehavior\"\t\t\"1\"");
+                if std::fs::write(&manifest_path, new_content).is_ok() {
+                    self.log_vdf_edit("setup", &manifest_path, steam_app_id, "AutoUpdateBehavior", "0", "1");
+                    self.prompter.info(
+                        &t!("installer.auto_update_setting_changed"),
+                        &t!("installer.steam_auto_update_success_message")
+                    );
                 }
             }
         }
@@ -379,74 +879,208 @@ impl Installer {
         Ok(())
     }
 
-    pub fn install(&self) -> Result<(), Error> {
-        let initial_dll_path = self.get_current_target_path().ok_or(Error::NoInstallDir)?;
+    fn list_exes_in_dir(dir: &Path) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut exes: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.to_ascii_lowercase().ends_with(".exe"))
+            .collect();
+        exes.sort();
+        exes
+    }
 
-        std::fs::create_dir_all(initial_dll_path.parent().unwrap())?;
-        let mut file = File::create(&initial_dll_path)?;
+    /// Re-classifies the install dir if no game version has been determined yet. If it's still
+    /// unknown, the GUI asks which version it is, showing the executables found.
+    fn resolve_game_version(&mut self) -> Result<(), Error> {
+        if self.game_version.is_some() {
+            return Ok(());
+        }
+        let Some(install_dir) = self.install_dir.clone() else {
+            return Ok(());
+        };
 
-        #[cfg(feature = "compress_dll")]
-        file.write(&include_bytes_zstd!("hachimi.dll", 19))?;
+        if let Some(version) = self.detect_version_from_dir(&install_dir) {
+            self.game_version = Some(version);
+            return Ok(());
+        }
 
-        #[cfg(not(feature = "compress_dll"))]
-        file.write(include_bytes!("../hachimi.dll"))?;
+        // Only the GUI asks, the CLI fails with the list instead
+        let error = Error::UnknownGameVersion(Self::list_exes_in_dir(&install_dir));
+        if !self.prompter.wants_optional_prompts() {
+            return Err(error);
+        }
+        let chosen_version = GameVersion::VALUES.iter()
+            .copied()
+            .find(|version| self.prompter.confirm(
+                &t!("installer.install"),
+                &format!("{}\n\n{}", error, t!("installer.confirm_game_version", version = version.display_name()))
+            ));
+        let Some(version) = chosen_version else {
+            return Err(error);
+        };
+        self.game_version = Some(version);
+        self.set_detected_dir(version, install_dir);
+        Ok(())
+    }
 
-        let install_path = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+    /// How much data patching the Steam executable writes and roughly how long it takes,
+    /// unless it has been patched already. The output is about as large as the original,
+    /// and it's written once and read back for verification.
+    pub fn estimate_patch(&self) -> Option<PatchEstimate> {
+        if !self.patches_steam_exe() {
+            return None;
+        }
+        let install_dir = self.install_dir.as_ref()?;
+        let exe_path = install_dir.join(GameVersion::Steam.exe_name());
+        if exe_path.with_extension("exe.bak").is_file() {
+            return None;
+        }
+        let size = std::fs::metadata(&exe_path).ok()?.len();
+        let temp_dir = self.temp_dir.as_deref().unwrap_or(install_dir);
+        let speed = utils::measure_write_speed(temp_dir)?;
+        Some(PatchEstimate {
+            size,
+            seconds: (size * 2).div_ceil(speed.max(1))
+        })
+    }
 
-        const EXPECTED_ORIGINAL_HASH: &str = "11015e76281aeed9c31edaecdcbed73013c5b26d4b024a89b02563f250ae61a7";
-        const EXPECTED_PATCHED_HASH: &str = "9d6955463a0a509a2355d2227a4ee9ef0ca5da3f0f908b0c846a1e3c218cb703";
+    /// Whether the file at `path` is a PE image for the same machine as `original`.
+    fn is_same_kind_of_pe(original: &[u8], path: &Path) -> bool {
+        let Ok(map) = pelite::FileMap::open(utils::extended_length_path(path)) else {
+            return false;
+        };
+        let machine = utils::read_pe_machine(map.as_ref());
+        machine.is_some() && machine == utils::read_pe_machine(original)
+    }
 
-        match self.game_version {
-            Some(GameVersion::DMM) => {},
-            Some(GameVersion::SteamGlobal) => {},
-            Some(GameVersion::Steam) => {
-                let steam_exe_path = install_path.join("UmamusumePrettyDerby_Jpn.exe");
-                let backup_exe_path = steam_exe_path.with_extension("exe.bak");
+    /// Makes sure a custom target won't overwrite one of the game's own DLLs.
+    fn check_custom_target(&self, path: &Path) -> Result<(), Error> {
+        let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+            return Ok(());
+        };
+        if self.custom_target.is_none() || file_name.eq_ignore_ascii_case(self.target.dll_name()) {
+            return Ok(());
+        }
 
-                let needs_patching = match utils::verify_file_hash(&steam_exe_path, EXPECTED_ORIGINAL_HASH) {
-                    Ok(_) => {
-                        true
-                    }
-                    Err(original_hash_err) => {
-                        match utils::verify_file_hash(&steam_exe_path, EXPECTED_PATCHED_HASH) {
-                            Ok(_) => {
-                                false
-                            }
-                            Err(_) => {
-                                let file_name_str = steam_exe_path
-                                    .file_name()
-                                    .unwrap_or_default()
-                                    .to_string_lossy();
-
-                                let error_msg = t!(
-                                    "installer.error_verification_body",
-                                    file_name = file_name_str,
-                                    details = original_hash_err.to_string()
-                                );
-                                return Err(Error::VerificationError(error_msg));
-                            }
-                        }
-                    }
-                };
+        if RESERVED_DLL_NAMES.iter().any(|name| name.eq_ignore_ascii_case(file_name)) {
+            return Err(Error::CustomTargetCollision(file_name.to_owned()));
+        }
+        if !self.force_custom_target {
+            // A file that can't be identified could just as well be another mod's or the game's
+            let foreign = match Self::read_target_status(path) {
+                TargetStatus::Absent => false,
+                TargetStatus::Present(info) => !info.is_hachimi(),
+                TargetStatus::Unreadable(_) => true
+            };
+            if foreign {
+                return Err(Error::CustomTargetCollision(file_name.to_owned()));
+            }
+        }
+        Ok(())
+    }
 
-                if needs_patching {
-                    std::fs::copy(&steam_exe_path, &backup_exe_path)?;
+    /// Fails early if the install dir can't be written to, rather than after patching. Also warns if
+    /// it's compressed, since writing the patched Steam executable is much slower there, and if the
+    /// temp dir is on another volume, since files are then copied into place instead of moved.
+    fn check_install_dir_writable(&self) -> Result<(), Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+        if !utils::is_dir_writable(install_dir) {
+            return Err(Error::InstallDirNotWritable(install_dir.clone()));
+        }
+        if let Some(temp_dir) = &self.temp_dir {
+            if !utils::is_same_volume(temp_dir, install_dir) {
+                self.prompter.warn(
+                    &t!("installer.warning"),
+                    &t!("installer.temp_dir_other_volume", temp_dir = temp_dir.display())
+                );
+            }
+        }
+        let will_patch = self.patches_steam_exe() &&
+            !Self::steam_exe_path(install_dir).with_extension("exe.bak").is_file();
+        if will_patch && utils::is_compressed(install_dir) {
+            self.prompter.warn(&t!("installer.warning"), &t!("installer.install_dir_compressed"));
+        }
+        Ok(())
+    }
 
-                    let original_exe_data = std::fs::read(&steam_exe_path)?;
-                    let compressed_patch_data = include_bytes!("../umamusume.patch.zst");
-                    let mut patch_data = Vec::new();
-                    let mut decoder = zstd::Decoder::new(&compressed_patch_data[..])?;
-                    decoder.read_to_end(&mut patch_data)?;
+    /// Checks that `required` bytes are free. Only the install dir's drive is checked,
+    /// the plugin shim's system dir is assumed to have room.
+    fn check_disk_space(&self, required: u64) -> Result<(), Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
 
-                    let temp_exe_path = steam_exe_path.with_extension("exe.tmp");
+        let Some(available) = utils::get_free_disk_space(install_dir) else {
+            return Ok(());
+        };
+        if available < required {
+            return Err(Error::InsufficientDiskSpace(required, available));
+        }
+        Ok(())
+    }
 
-                    utils::apply_patch(&original_exe_data, &patch_data, &temp_exe_path)
-                        .map_err(|e| Error::Generic(e.to_string().into()))?;
+    /// Space needed for a backup and a patched copy of the Steam executable. An existing backup
+    /// is overwritten, so the space it takes counts as free. Counted even if the executable turns
+    /// out to be patched already, since that's only known after hashing it.
+    fn patch_disk_space(install_dir: &Path) -> u64 {
+        let exe_path = Self::steam_exe_path(install_dir);
+        // The backup and the patched copy are compressed too in a compressed folder
+        let compressed = utils::is_compressed(install_dir);
+        let size_on_disk = |path: &Path| if compressed {
+            utils::get_allocated_size(path)
+        } else {
+            std::fs::metadata(path).ok().map(|metadata| metadata.len())
+        };
 
-                    std::fs::remove_file(&steam_exe_path)?;
-                    std::fs::rename(&temp_exe_path, &steam_exe_path)?;
-                }
-            },
+        let Some(exe_size) = size_on_disk(&exe_path) else {
+            return 0;
+        };
+        let backup_size = size_on_disk(&exe_path.with_extension("exe.bak")).unwrap_or(0);
+        (exe_size * 2).saturating_sub(backup_size)
+    }
+
+    /// Can be cancelled before the DLL is written, and while verifying or patching the Steam executable.
+    /// A cancelled patch removes its temporary file and leaves the original executable untouched,
+    /// but the DLL is kept, same as when patching fails.
+    pub fn install(&mut self) -> Result<(), Error> {
+        self.resolve_game_version()?;
+        self.check_cancelled()?;
+
+        let initial_dll_path = self.get_current_target_path().ok_or(Error::NoInstallDir)?;
+        self.check_custom_target(&initial_dll_path)?;
+        self.check_install_dir_writable()?;
+
+        let dll_data = match &self.dll_override {
+            Some(payload_override) => Cow::Owned(payload::read_override(payload_override, Some("Hachimi"))?),
+            None => payload::hachimi_dll()?
+        };
+        self.check_architecture(&dll_data)?;
+
+        // Checked before writing anything, so a full drive doesn't leave the DLL installed
+        // next to an unpatched executable
+        let mut required_space = dll_data.len() as u64;
+        if self.patches_steam_exe() {
+            let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+            required_space += Self::patch_disk_space(install_dir);
+        }
+        self.check_disk_space(required_space)?;
+
+        std::fs::create_dir_all(initial_dll_path.parent().unwrap())?;
+        utils::clear_readonly(&initial_dll_path)?;
+        self.check_cancelled()?;
+        let writing_stage = t!("installer.progress.writing", file_name = initial_dll_path.file_name().unwrap_or_default().to_string_lossy());
+        self.report_progress(&writing_stage, 0, 1);
+        utils::write_file_atomic(&initial_dll_path, &dll_data, self.temp_dir.as_deref()).map_err(|e| Error::from_locked_file(&initial_dll_path, e))?;
+        self.report_progress(&writing_stage, 1, 1);
+
+        let install_path = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+
+        match self.game_version {
+            Some(GameVersion::DMM) => {},
+            Some(GameVersion::SteamGlobal) => {},
+            Some(GameVersion::Steam) if !self.patches_steam_exe() => {},
+            Some(GameVersion::Steam) => self.patch_steam_exe(install_path)?,
             None => {
                 return Err(Error::IoError(std::io::Error::new(
                     std::io::ErrorKind::NotFound,
@@ -460,28 +1094,171 @@ impl Installer {
         Ok(())
     }
 
+    /// Classifies the Steam (Japan) executable by its fast fingerprint. `None` if it can't be
+    /// computed or any of the patches has no fingerprint, since then an unknown one proves nothing.
+    fn match_steam_exe_fingerprint(patches: &[SteamPatch], exe_path: &Path) -> Option<FingerprintMatch> {
+        if patches.iter().any(|patch| patch.original_fingerprint.is_none() || patch.patched_fingerprint.is_none()) {
+            return None;
+        }
+        let fingerprint = utils::fingerprint_file(exe_path).ok()?;
+
+        Some(if patches.iter().any(|patch| patch.patched_fingerprint.as_ref() == Some(&fingerprint)) {
+            FingerprintMatch::Patched
+        } else if patches.iter().any(|patch| patch.original_fingerprint.as_ref() == Some(&fingerprint)) {
+            FingerprintMatch::Original
+        } else {
+            FingerprintMatch::Unknown
+        })
+    }
+
+    /// Verifies the Steam (Japan) executable and patches it, unless it's patched already.
+    fn patch_steam_exe(&self, install_path: &Path) -> Result<(), Error> {
+        let steam_exe_path = Self::steam_exe_path(install_path);
+        let backup_exe_path = steam_exe_path.with_extension("exe.bak");
+
+        let exe_name = GameVersion::Steam.exe_name();
+        let verifying_stage = t!("installer.progress.verifying", file_name = exe_name);
+
+        let patches = payload::steam_patches()?;
+        let verification_error = |details: String| {
+            let file_name_str = steam_exe_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+
+            Error::VerificationError(t!(
+                "installer.error_verification_body",
+                file_name = file_name_str,
+                details = details
+            ))
+        };
+
+        // Hashing the whole executable takes a while, so rule out unsupported builds quickly first
+        if Self::match_steam_exe_fingerprint(&patches, &steam_exe_path) == Some(FingerprintMatch::Unknown) {
+            return Err(verification_error(t!("details.hash_error.unsupported_build")));
+        }
+
+        let exe_hash = utils::hash_file(&steam_exe_path, &self.cancel, &mut |done, total| {
+            self.report_progress(&verifying_stage, done, total)
+        });
+        self.check_cancelled()?;
+
+        let exe_hash = exe_hash.map_err(verification_error)?;
+
+        let patch = if patches.iter().any(|patch| patch.patched_hash == exe_hash) {
+            None
+        } else if let Some(patch) = patches.iter().find(|patch| patch.original_hash == exe_hash) {
+            Some(patch)
+        } else {
+            let expected = patches.last().map(|patch| patch.original_hash.as_str()).unwrap_or_default();
+            return Err(verification_error(t!("details.hash_error.mismatch", expected = expected, found = exe_hash)));
+        };
+
+        if let Some(patch) = patch {
+            utils::clear_readonly(&backup_exe_path)?;
+            std::fs::copy(&steam_exe_path, &backup_exe_path)?;
+
+            let original_exe_data = std::fs::read(&steam_exe_path)?;
+            let mut patch_data = Vec::new();
+            let mut decoder = zstd::Decoder::new(&patch.data[..])?;
+            decoder.read_to_end(&mut patch_data)?;
+
+            let temp_exe_path = utils::temp_path_for(&steam_exe_path, self.temp_dir.as_deref());
+
+            let patching_stage = t!("installer.progress.patching", file_name = exe_name);
+            let res = utils::apply_patch(&original_exe_data, &patch_data, &temp_exe_path, &self.cancel, &mut |done, total| {
+                self.report_progress(&patching_stage, done, total)
+            });
+            if let Err(e) = res {
+                _ = std::fs::remove_file(&temp_exe_path);
+                self.check_cancelled()?;
+                return Err(Error::Generic(e.to_string().into()));
+            }
+            if let Err(e) = self.check_cancelled() {
+                _ = std::fs::remove_file(&temp_exe_path);
+                return Err(e);
+            }
+
+            // A patch applied to the wrong base can produce garbage that only fails at launch
+            if !Self::is_same_kind_of_pe(&original_exe_data, &temp_exe_path) {
+                _ = std::fs::remove_file(&temp_exe_path);
+                return Err(Error::PatchProducedInvalidPe(exe_name.to_owned()));
+            }
+
+            // Patches from the sidecar aren't checked at build time, so check their output instead
+            let patched_hash = utils::hash_file(&temp_exe_path, &self.cancel, &mut |done, total| {
+                self.report_progress(&verifying_stage, done, total)
+            });
+            if patched_hash.as_deref() != Ok(patch.patched_hash.as_str()) {
+                _ = std::fs::remove_file(&temp_exe_path);
+                self.check_cancelled()?;
+                let found = patched_hash.unwrap_or_else(|e| e);
+                return Err(Error::VerificationError(
+                    t!("details.hash_error.mismatch", expected = patch.patched_hash, found = found)
+                ));
+            }
+
+            utils::clear_readonly(&steam_exe_path)?;
+            utils::move_file(&temp_exe_path, &steam_exe_path)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the Steam (Japan) executable was patched before, but has since been replaced,
+    /// e.g. by a game update. Hashes the whole executable unless the patches have fingerprints,
+    /// so it may take a moment.
+    pub fn needs_repatch(&self) -> bool {
+        let Some(install_dir) = self.install_dir.as_ref() else {
+            return false;
+        };
+        let exe_path = Self::steam_exe_path(install_dir);
+        if !self.patches_steam_exe() || !exe_path.with_extension("exe.bak").is_file() {
+            return false;
+        }
+        // The backup may have been kept on purpose after uninstalling
+        if self.get_hachimi_installed_target(TargetScope::All).is_none() {
+            return false;
+        }
+
+        let Ok(patches) = payload::steam_patches() else {
+            return false;
+        };
+        match Self::match_steam_exe_fingerprint(&patches, &exe_path) {
+            Some(FingerprintMatch::Patched) => return false,
+            Some(FingerprintMatch::Original | FingerprintMatch::Unknown) => return true,
+            None => {}
+        }
+        let Ok(exe_hash) = utils::hash_file(&exe_path, &self.cancel, &mut |_, _| {}) else {
+            return false;
+        };
+        !patches.iter().any(|patch| patch.patched_hash == exe_hash)
+    }
+
+    /// Patches the Steam (Japan) executable again after it was replaced, see `needs_repatch`.
+    pub fn repatch(&self) -> Result<(), Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+        self.check_cancelled()?;
+        self.patch_steam_exe(install_dir)
+    }
+
     pub fn post_install(&self) -> Result<(), Error> {
+        self.check_cancelled()?;
+
         match self.get_install_method(self.target) {
             InstallMethod::DotLocal => {
                 // Install Cellar
-                let main_dll_path = self.get_current_target_path().ok_or(Error::NoInstallDir)?;
-                let parent_dir = main_dll_path.parent().unwrap();
-
-                let path = parent_dir.join("apphelp.dll");
+                let path = self.get_cellar_path().ok_or(Error::NoInstallDir)?;
+                let cellar_data = match &self.cellar_override {
+                    Some(payload_override) => Cow::Owned(payload::read_override(payload_override, None)?),
+                    None => payload::cellar_dll()?
+                };
+                self.backup_foreign_apphelp(&path, &cellar_data)?;
                 std::fs::create_dir_all(path.parent().unwrap())?;
-                let mut file = File::create(&path)?;
-
-                #[cfg(feature = "compress_dll")]
-                file.write(&include_bytes_zstd!("cellar.dll", 19))?;
-
-                #[cfg(not(feature = "compress_dll"))]
-                file.write(include_bytes!("../cellar.dll"))?;
+                utils::clear_readonly(&path)?;
+                utils::write_file_atomic(&path, &cellar_data, self.temp_dir.as_deref()).map_err(|e| Error::from_locked_file(&path, e))?;
 
                 // Check for DLL redirection
-                match Hive::LocalMachine.open(
-                    r"SOFTWARE\Microsoft\Windows NT\CurrentVersion\Image File Execution Options",
-                    registry::Security::Read | registry::Security::SetValue
-                ) {
+                match Hive::LocalMachine.open(IFEO_KEY_PATH, registry::Security::Read | registry::Security::SetValue) {
                     Ok(regkey) => {
                         if regkey.value("DevOverrideEnable")
                             .ok()
@@ -491,34 +1268,24 @@ impl Installer {
                             })
                             .unwrap_or(0) == 0
                         {
-                            let res = unsafe {
-                                MessageBoxW(
-                                    self.hwnd.as_ref(),
-                                    &HSTRING::from(t!("installer.dotlocal_not_enabled")),
-                                    &HSTRING::from(t!("installer.install")),
-                                    MB_ICONINFORMATION | MB_OKCANCEL
-                                )
-                            };
-                            if res == IDOK {
+                            if self.auto_enable_dll_redirection ||
+                                self.prompter.confirm(&t!("installer.install"), &t!("installer.dotlocal_not_enabled"))
+                            {
                                 regkey.set_value("DevOverrideEnable", &registry::Data::U32(1))?;
-                                unsafe {
-                                    MessageBoxW(
-                                        self.hwnd.as_ref(),
-                                        &HSTRING::from(t!("installer.restart_to_apply")),
-                                        &HSTRING::from(t!("installer.dll_redirection_enabled")),
-                                        MB_ICONINFORMATION | MB_OK
-                                    );
-                                }
+                                self.prompter.info(
+                                    &t!("installer.dll_redirection_enabled"),
+                                    &t!("installer.restart_to_apply")
+                                );
+                            } else {
+                                utils::log(&t!("installer.dll_redirection_left_disabled"));
                             }
                         }
                     },
                     Err(e) => {
-                        unsafe { MessageBoxW(
-                            self.hwnd.as_ref(),
-                            &HSTRING::from(t!("installer.failed_open_ifeo", error = e)),
-                            &HSTRING::from(t!("installer.warning")),
-                            MB_OK | MB_ICONWARNING
-                        )};
+                        self.prompter.warn(
+                            &t!("installer.warning"),
+                            &t!("installer.failed_open_ifeo", error = e)
+                        );
                     }
                 }
             },
@@ -528,7 +1295,11 @@ impl Installer {
 
                 if src_dll.exists() {
                     std::fs::create_dir_all(dest_dll.parent().unwrap())?;
+                    utils::clear_readonly(&dest_dll)?;
                     std::fs::copy(&src_dll, &dest_dll)?;
+                    // The copy keeps the read-only attribute, which would block the next install
+                    utils::clear_readonly(&dest_dll)?;
+                    utils::clear_readonly(&src_dll)?;
                     std::fs::remove_file(&src_dll)?;
                 }
             },
@@ -537,100 +1308,653 @@ impl Installer {
         Ok(())
     }
 
-    fn check_and_prompt_restore_steam_autoupdate(&self) -> Result<(), Error> {
-        let (steam_app_id_str, install_dir) = match self.game_version {
-            Some(GameVersion::Steam) => ("3564400", self.install_dir.as_ref()),
-            _ => return Ok(()),
-        };
-
-        if let Some(install_dir) = install_dir {
-            if let Some(steamapps_path) = find_steamapps_folder(install_dir) {
-                let manifest_path = steamapps_path.join(format!("appmanifest_{}.acf", steam_app_id_str));
-                let backup_path = manifest_path.with_extension("acf.bak");
-
-                if backup_path.is_file() {
-                    let res = unsafe {
-                        MessageBoxW(
-                            self.hwnd.as_ref(),
-                            &HSTRING::from(t!("installer.steam_auto_update_restore_prompt")),
-                            &HSTRING::from(t!("installer.restore_auto_update_setting")),
-                            MB_ICONQUESTION | MB_YESNO
-                        )
-                    };
-
-                    if res == IDYES {
-                        if let (Ok(live_content), Ok(backup_content)) = (std::fs::read_to_string(&manifest_path), std::fs::read_to_string(&backup_path)) {
-                            let original_setting = backup_content.lines().find(|l| l.contains("\"AutoUpdateBehavior\""));
-                            let current_setting = live_content.lines().find(|l| l.contains("\"AutoUpdateBehavior\""));
-
-                            if let (Some(original), Some(current)) = (original_setting, current_setting) {
-                                let new_content = live_content.replace(current, original);
-                                if std::fs::write(&manifest_path, new_content).is_ok() {
-                                    _ = std::fs::remove_file(&backup_path);
-
-                                    unsafe {
-                                        MessageBoxW(
-                                            self.hwnd.as_ref(),
-                                            &HSTRING::from(t!("installer.steam_auto_update_restored_message")),
-                                            &HSTRING::from(t!("installer.setting_restored")),
-                                            MB_ICONINFORMATION | MB_OK
-                                        );
-                                    }
-                                }
+    /// Backs up an apphelp.dll that isn't Cellar so it can be restored on uninstall,
+    /// after asking whether to replace it.
+    fn backup_foreign_apphelp(&self, path: &Path, cellar_data: &[u8]) -> Result<(), Error> {
+        let Ok(existing) = std::fs::read(path) else {
+            return Ok(());
+        };
+        if existing == cellar_data {
+            return Ok(());
+        }
+        let name = utils::read_pe_version_info(&existing)
+            .and_then(|version_info| utils::read_version_string(version_info, "ProductName"));
+        if name.as_deref() == Some("Cellar") {
+            return Ok(());
+        }
+
+        if self.prompter.wants_optional_prompts() &&
+            !self.prompter.confirm(&t!("installer.install"), &t!("installer.replace_foreign_apphelp"))
+        {
+            return Err(Error::Cancelled);
+        }
+
+        let backup_path = path.with_extension("dll.bak");
+        if !backup_path.exists() {
+            std::fs::copy(path, &backup_path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the setting was restored.
+    fn check_and_prompt_restore_steam_autoupdate(&self) -> Result<bool, Error> {
+        let Some(steam_app_id) = self.steam_app_id() else {
+            return Ok(false);
+        };
+        if let Some(manifest_path) = self.steam_manifest_path() {
+            let backup_path = manifest_path.with_extension("acf.bak");
+
+            if backup_path.is_file() {
+                if self.prompter.confirm(
+                    &t!("installer.restore_auto_update_setting"),
+                    &t!("installer.steam_auto_update_restore_prompt")
+                ) {
+                    if let (Ok(live_content), Ok(backup_content)) = (std::fs::read_to_string(&manifest_path), std::fs::read_to_string(&backup_path)) {
+                        let original_setting = backup_content.lines().find(|l| l.contains("\"AutoUpdateBehavior\""));
+                        let current_setting = live_content.lines().find(|l| l.contains("\"AutoUpdateBehavior\""));
+
+                        if let (Some(original), Some(current)) = (original_setting, current_setting) {
+                            let new_content = live_content.replace(current, original);
+                            if std::fs::write(&manifest_path, new_content).is_ok() {
+                                self.log_vdf_edit(
+                                    "restore", &manifest_path, steam_app_id, "AutoUpdateBehavior",
+                                    vdf_line_value(current), vdf_line_value(original)
+                                );
+                                _ = std::fs::remove_file(&backup_path);
+
+                                self.prompter.info(
+                                    &t!("installer.setting_restored"),
+                                    &t!("installer.steam_auto_update_restored_message")
+                                );
+                                return Ok(true);
                             }
                         }
                     }
                 }
             }
         }
+        Ok(false)
+    }
+
+    /// Backups made by the installer for its own bookkeeping, whether or not they exist.
+    fn backup_file_paths(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let Some(install_dir) = self.install_dir.as_ref() else {
+            return paths;
+        };
+
+        if self.game_version == Some(GameVersion::Steam) {
+            paths.push(Self::steam_exe_path(install_dir).with_extension("exe.bak"));
+        }
+        if let Some(manifest_path) = self.steam_manifest_path() {
+            paths.push(manifest_path.with_extension("acf.bak"));
+        }
+        if self.get_install_method(self.target) == InstallMethod::DotLocal {
+            if let Some(cellar_path) = self.get_cellar_path() {
+                paths.push(cellar_path.with_extension("dll.bak"));
+            }
+        }
+        paths
+    }
+
+    /// Every file that `install` and `post_install` may write with the current selection, including
+    /// temp files and backups, without writing anything. Useful for setting up antivirus exclusions.
+    pub fn planned_files(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let (Some(install_dir), Some(target_path)) = (self.install_dir.as_ref(), self.get_current_target_path()) else {
+            return paths;
+        };
+        let temp_dir = self.temp_dir.as_deref();
+
+        paths.push(utils::temp_path_for(&target_path, temp_dir));
+        paths.push(target_path);
+        if self.patches_steam_exe() {
+            let exe_path = Self::steam_exe_path(install_dir);
+            paths.push(utils::temp_path_for(&exe_path, temp_dir));
+            paths.push(exe_path);
+        }
+        if let Some(manifest_path) = self.steam_manifest_path() {
+            paths.push(manifest_path);
+        }
+        match self.get_install_method(self.target) {
+            InstallMethod::DotLocal => {
+                if let Some(cellar_path) = self.get_cellar_path() {
+                    paths.push(utils::temp_path_for(&cellar_path, temp_dir));
+                    paths.push(cellar_path);
+                }
+            },
+            InstallMethod::PluginShim => paths.extend(self.get_dest_plugin_path()),
+            InstallMethod::Direct => {}
+        }
+        paths.extend(self.backup_file_paths());
+        paths
+    }
+
+    /// Where the audit log of changes to Steam's files is written, in the local app data folder.
+    pub fn vdf_audit_log_path() -> PathBuf {
+        let local_app_data = unsafe { SHGetKnownFolderPath(&FOLDERID_LocalAppData, KF_FLAG_DEFAULT, None) }
+            .ok()
+            .and_then(|wstr| unsafe { wstr.to_string() }.ok());
+        local_app_data
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join(VDF_AUDIT_LOG_FILE_NAME)
+    }
+
+    /// Appends an edit made to one of Steam's files to the audit log if `vdf_audit_log` is set,
+    /// so that it can be reverted by hand. Values are escaped to keep each entry on one line.
+    fn log_vdf_edit(&self, action: &str, path: &Path, app_id: u32, key: &str, old_value: &str, new_value: &str) {
+        use std::io::Write;
+
+        if !self.vdf_audit_log {
+            return;
+        }
+        let log_path = Self::vdf_audit_log_path();
+        let entry = format!(
+            "{}\t{}\tapp_id={}\tfile={:?}\tkey={:?}\told={:?}\tnew={:?}\n",
+            chrono::Local::now().to_rfc3339(), action, app_id, path, key, old_value, new_value
+        );
+        let result = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .and_then(|mut file| file.write_all(entry.as_bytes()));
+        if let Err(e) = result {
+            self.prompter.warn(
+                &t!("installer.warning"),
+                &t!("installer.failed_write_vdf_audit_log", path = log_path.display(), error = e)
+            );
+        }
+    }
+
+    /// Where `write_av_exclusions` writes the script by default, next to the installer.
+    pub fn av_exclusions_path() -> PathBuf {
+        std::env::current_exe()
+            .map(|exe| exe.with_file_name(AV_EXCLUSIONS_FILE_NAME))
+            .unwrap_or_else(|_| std::env::temp_dir().join(AV_EXCLUSIONS_FILE_NAME))
+    }
+
+    /// Writes a PowerShell script that adds Windows Defender exclusions for `planned_files` and
+    /// the game process. It's meant to be reviewed and run by hand, so writing it needs no admin rights.
+    pub fn write_av_exclusions(&self, path: &Path) -> Result<(), Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+        // Single quoted strings only treat quotes as special, which are escaped by doubling them
+        let quote = |path: &Path| format!("'{}'", path.to_string_lossy().replace('\'', "''"));
+
+        let mut script = String::from(
+            "# Windows Defender exclusions for the files the Hachimi installer writes.\r\n\
+             # Run this in PowerShell as administrator, after removing any lines you don't want.\r\n"
+        );
+        for file_path in self.planned_files() {
+            script += &format!("Add-MpPreference -ExclusionPath {}\r\n", quote(&file_path));
+        }
+        script += &format!(
+            "Add-MpPreference -ExclusionProcess {}\r\n",
+            quote(&install_dir.join(self.game_exe_name()))
+        );
+
+        std::fs::write(path, script)?;
         Ok(())
     }
 
-    pub fn uninstall(&self) -> Result<(), Error> {
+    /// Writes the exclusions script next to the installer and runs it. Requires admin rights.
+    pub fn add_av_exclusions(&self) -> Result<(), Error> {
+        use std::os::windows::process::CommandExt;
+        use windows::Win32::System::Threading::CREATE_NO_WINDOW;
+
+        if !utils::is_elevated() {
+            return Err(Error::NotElevated);
+        }
+        let script_path = Self::av_exclusions_path();
+        self.write_av_exclusions(&script_path)?;
+
+        let status = std::process::Command::new("powershell.exe")
+            .args(["-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-File"])
+            .arg(&script_path)
+            .creation_flags(CREATE_NO_WINDOW.0)
+            .status()?;
+        if !status.success() {
+            return Err(Error::Generic(format!("PowerShell exited with {}", status).into()));
+        }
+        Ok(())
+    }
+
+    pub fn list_backup_files(&self) -> Vec<PathBuf> {
+        self.backup_file_paths()
+            .into_iter()
+            .filter(|path| path.is_file())
+            .collect()
+    }
+
+    /// Removes the backup files and returns their paths. Refuses to do so while Hachimi is
+    /// installed, since uninstalling restores the original files from them.
+    pub fn clean_backup_files(&self, delete_mode: DeleteMode) -> Result<Vec<PathBuf>, Error> {
+        if self.get_hachimi_installed_target(TargetScope::All).is_some() {
+            return Err(Error::BackupsInUse);
+        }
+
+        let paths = self.list_backup_files();
+        for path in &paths {
+            delete_mode.remove_file(path).map_err(|e| Error::from_locked_file(path, e))?;
+        }
+        Ok(paths)
+    }
+
+    pub fn uninstall(&self, options: UninstallOptions) -> Result<UninstallFollowUp, Error> {
+        let delete_mode = options.delete_mode;
         let path = self.get_current_target_path().ok_or(Error::NoInstallDir)?;
-        std::fs::remove_file(&path)?;
+
+        // Never delete a file that isn't ours (e.g. the game's own UnityPlayer.dll) unless forced
+        if !options.force {
+            if let Some(version_info) = Self::read_version_info(&path) {
+                if !version_info.is_hachimi() {
+                    let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                    return Err(Error::RefusingToDeleteForeignFile(file_name));
+                }
+            }
+        }
+
+        delete_mode.remove_file(&path).map_err(|e| Error::from_locked_file(&path, e))?;
 
         match self.get_install_method(self.target) {
             InstallMethod::DotLocal => {
                 let parent = path.parent().unwrap();
-                // Also delete Cellar
-                _ = std::fs::remove_file(parent.join("apphelp.dll"));
-                // Only remove if its empty
-                _ = std::fs::remove_dir(parent);
-            },
-            InstallMethod::PluginShim => {
-                let dest_dll = self.get_dest_plugin_path().ok_or(Error::NoInstallDir)?;
-                let src_dll = self.get_src_plugin_path().ok_or(Error::NoInstallDir)?;
-                if !src_dll.exists() {
-                    std::fs::copy(&dest_dll, &src_dll)?;
-                    std::fs::remove_file(&dest_dll)?;
+                // Also delete Cellar, restoring the apphelp.dll it replaced if there was one
+                let apphelp_path = parent.join("apphelp.dll");
+                _ = delete_mode.remove_file(&apphelp_path);
+                let apphelp_backup_path = apphelp_path.with_extension("dll.bak");
+                if apphelp_backup_path.is_file() {
+                    std::fs::rename(&apphelp_backup_path, &apphelp_path)?;
                 }
+                Self::remove_local_dir_if_empty(parent);
             },
+            InstallMethod::PluginShim => self.restore_original_plugin(delete_mode)?,
             InstallMethod::Direct => {}
         }
 
+        self.remove_stray_targets(delete_mode)?;
+
+        let mut kept_exe_backup = false;
         if self.game_version == Some(GameVersion::Steam) {
             let install_path = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
-            let exe_path = install_path.join("UmamusumePrettyDerby_Jpn.exe");
+            let exe_path = Self::steam_exe_path(install_path);
             let backup_path = exe_path.with_extension("exe.bak");
 
             if backup_path.is_file() {
-                std::fs::remove_file(&exe_path)?;
-                std::fs::rename(&backup_path, &exe_path)?;
+                delete_mode.remove_file(&exe_path)?;
+                if self.keep_exe_backup {
+                    std::fs::copy(&backup_path, &exe_path)?;
+                    kept_exe_backup = true;
+                } else {
+                    std::fs::rename(&backup_path, &exe_path)?;
+                }
+            }
+        }
+
+        // Steam keeps its own copy of the manifest while running and may write it back
+        let restored_auto_update = self.check_and_prompt_restore_steam_autoupdate()?;
+
+        Ok(UninstallFollowUp {
+            restart_steam: restored_auto_update && utils::is_steam_running(),
+            kept_exe_backup
+        })
+    }
+
+    /// Files managed by the installer that are still there after `uninstall`, e.g. because they were
+    /// locked, along with the processes locking them. Empty if everything was removed.
+    pub fn verify_uninstall(&self) -> Vec<String> {
+        let is_product = |path: &Path, product_name: &str| matches!(
+            Self::read_target_status(path),
+            TargetStatus::Present(info) if info.name.as_deref() == Some(product_name)
+        );
+
+        let mut paths = Vec::new();
+        if let Some(target_path) = self.get_current_target_path().filter(|path| is_product(path, "Hachimi")) {
+            paths.push(target_path);
+        }
+        if self.get_install_method(self.target) == InstallMethod::DotLocal {
+            paths.extend(self.get_cellar_path().filter(|path| is_product(path, "Cellar")));
+        }
+        paths.extend(self.planned_files().into_iter().filter(|path| {
+            path.extension().is_some_and(|ext| ext == "tmp") && path.is_file()
+        }));
+        let kept_backup_path = self.install_dir.as_ref()
+            .filter(|_| self.keep_exe_backup)
+            .map(|dir| Self::steam_exe_path(dir).with_extension("exe.bak"));
+        paths.extend(self.list_backup_files().into_iter().filter(|path| Some(path) != kept_backup_path.as_ref()));
+
+        paths.into_iter()
+            .map(|path| {
+                let processes = utils::get_locking_processes(&path);
+                if processes.is_empty() {
+                    path.display().to_string()
+                } else {
+                    t!("installer.leftover_locked", path = path.display(), processes = processes.join(", "))
+                }
+            })
+            .collect()
+    }
+
+    /// Uninstalls every Hachimi target from every detected install dir, regardless of the selection,
+    /// which is restored afterwards. Files that aren't Hachimi are never removed, even if `options.force` is set.
+    pub fn uninstall_all_detected(&mut self, options: UninstallOptions) -> Vec<(GameVersion, UninstallReport)> {
+        let options = UninstallOptions { force: false, ..options };
+        let install_dir = self.install_dir.take();
+        let game_version = self.game_version;
+        let target = self.target;
+        let custom_target = self.custom_target.take();
+
+        let mut reports = Vec::new();
+        for candidate in self.candidate_dirs() {
+            if candidate.hachimi.is_none() {
+                continue;
+            }
+            self.install_dir = Some(candidate.path);
+            self.game_version = Some(candidate.version);
+
+            for target in Target::VALUES {
+                if !matches!(self.get_target_status(*target), TargetStatus::Present(info) if info.is_hachimi()) {
+                    continue;
+                }
+                self.target = *target;
+                let Some(path) = self.get_current_target_path() else {
+                    continue;
+                };
+                let result = self.uninstall(options).map(|_| ());
+                reports.push((candidate.version, UninstallReport { target: *target, path, result }));
             }
         }
 
-        self.check_and_prompt_restore_steam_autoupdate()?;
+        self.install_dir = install_dir;
+        self.game_version = game_version;
+        self.target = target;
+        self.custom_target = custom_target;
+        reports
+    }
+
+    /// Removes a .local folder if there's nothing else in it. If it's a symlink or junction
+    /// to a folder elsewhere, only the link is removed, never the folder it points to.
+    fn remove_local_dir_if_empty(dir: &Path) {
+        let is_link = std::fs::symlink_metadata(dir).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_link {
+            // Removing a link succeeds even if its target isn't empty
+            if std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none()) {
+                _ = std::fs::remove_dir(dir);
+            }
+        } else {
+            // Fails if it isn't empty
+            _ = std::fs::remove_dir(dir);
+        }
+    }
 
+    fn restore_original_plugin(&self, delete_mode: DeleteMode) -> Result<(), Error> {
+        let dest_dll = self.get_dest_plugin_path().ok_or(Error::NoInstallDir)?;
+        let src_dll = self.get_src_plugin_path().ok_or(Error::NoInstallDir)?;
+        if !src_dll.exists() && dest_dll.is_file() {
+            std::fs::copy(&dest_dll, &src_dll)?;
+            delete_mode.remove_file(&dest_dll)?;
+        }
         Ok(())
     }
 
+    /// Removes Hachimi copies of the current target placed by the other install methods,
+    /// e.g. if it was installed while a different game version was selected.
+    fn remove_stray_targets(&self, delete_mode: DeleteMode) -> Result<(), Error> {
+        let install_dir = self.install_dir.as_ref().ok_or(Error::NoInstallDir)?;
+        let current_method = self.get_install_method(self.target);
+        let dll_name = self.custom_target.as_deref().unwrap_or(self.target.dll_name());
+
+        for method in InstallMethod::VALUES {
+            if *method == current_method {
+                continue;
+            }
+            // The shim lives in System32 and is shared by every install dir, so it only belongs to
+            // this one if it's DMM. Otherwise it would remove a working DMM install's shim.
+            if *method == InstallMethod::PluginShim && self.game_version != Some(GameVersion::DMM) {
+                continue;
+            }
+
+            let path = self.get_target_path_for_method(install_dir, self.game_version, *method, dll_name);
+            if !Self::read_version_info(&path).is_some_and(|info| info.is_hachimi()) {
+                continue;
+            }
+            delete_mode.remove_file(&path).map_err(|e| Error::from_locked_file(&path, e))?;
+
+            match method {
+                InstallMethod::DotLocal => {
+                    let parent = path.parent().unwrap();
+                    _ = delete_mode.remove_file(&parent.join("apphelp.dll"));
+                    Self::remove_local_dir_if_empty(parent);
+                },
+                InstallMethod::PluginShim => self.restore_original_plugin(delete_mode)?,
+                InstallMethod::Direct => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes Hachimi files from a directory that no longer contains a recognizable game executable.
+    fn remove_orphaned_files(&self, dir: &Path, delete_mode: DeleteMode) -> Result<(), Error> {
+        let is_hachimi = |path: &Path| Self::read_version_info(path).is_some_and(|info| info.is_hachimi());
+
+        let exe_names = GameVersion::VALUES.iter()
+            .map(|version| version.exe_name())
+            .chain(self.beta_exes.iter().map(|(_, exe_name)| exe_name.as_str()));
+        for exe_name in exe_names {
+            let local_dir = dir.join(format!("{}.local", exe_name));
+            let mut removed = false;
+            for target in Target::VALUES {
+                let path = local_dir.join(target.dll_name());
+                if is_hachimi(&path) {
+                    delete_mode.remove_file(&path).map_err(|e| Error::from_locked_file(&path, e))?;
+                    removed = true;
+                }
+            }
+            if removed {
+                _ = delete_mode.remove_file(&local_dir.join("apphelp.dll"));
+                Self::remove_local_dir_if_empty(&local_dir);
+            }
+        }
+
+        for target in Target::VALUES {
+            let path = dir.join(target.dll_name());
+            if is_hachimi(&path) {
+                delete_mode.remove_file(&path).map_err(|e| Error::from_locked_file(&path, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copies the preserved data that exists in `from` into `to`. Entries that don't exist are skipped.
+    fn copy_preserved_data(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        // The plugin shim moves the game's original plugin into the hachimi folder. Each install dir
+        // has its own, so a copy from another folder would replace the real one with a stale one.
+        let shim_plugin_dir = from.join("hachimi");
+        let is_shim_plugin = |path: &Path| {
+            path.parent() == Some(shim_plugin_dir.as_path()) &&
+                path.file_name().is_some_and(|name| Target::VALUES.iter().any(|target| {
+                    name.to_string_lossy().eq_ignore_ascii_case(target.dll_name())
+                }))
+        };
+
+        for relative_path in &self.preserved_data {
+            let src = from.join(relative_path);
+            if src.exists() {
+                utils::copy_recursive(&src, &to.join(relative_path), &is_shim_plugin)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the current target and everything installed alongside it, then installs from scratch.
+    /// Uninstalling never deletes the data folder, so nothing needs to be preserved.
+    pub fn reinstall(&mut self, options: UninstallOptions) -> Result<(), Error> {
+        if self.is_current_target_installed() {
+            self.uninstall(options)?;
+        }
+        self.pre_install()?;
+        self.install()?;
+        self.post_install()
+    }
+
+    /// Uninstalls from `from` and installs to `to`, e.g. after moving the game to another library.
+    /// If `from` no longer contains the game, only the Hachimi files left there are removed.
+    /// The preserved data is copied over, but not deleted from `from`.
+    pub fn migrate(&mut self, from: &Path, to: &Path, options: UninstallOptions) -> Result<MigrateReport, Error> {
+        if self.detect_version_from_dir(to).is_none() {
+            return Err(Error::InvalidInstallDir);
+        }
+
+        let uninstall = if self.detect_version_from_dir(from).is_some() {
+            let detected_dirs = (
+                self.dmm_install_dir.clone(),
+                self.steam_install_dir.clone(),
+                self.steam_global_install_dir.clone()
+            );
+            let res = self.set_install_dir(from.to_path_buf())
+                .and_then(|_| self.uninstall(options))
+                .map(|_| ());
+            // The old location must not replace the detected dir for its version
+            (self.dmm_install_dir, self.steam_install_dir, self.steam_global_install_dir) = detected_dirs;
+            res
+        } else {
+            self.remove_orphaned_files(from, options.delete_mode)
+        };
+
+        let install = self.set_install_dir(to.to_path_buf())
+            .and_then(|_| self.pre_install())
+            .and_then(|_| self.install())
+            .and_then(|_| self.post_install())
+            .and_then(|_| self.copy_preserved_data(from, to));
+
+        Ok(MigrateReport { uninstall, install })
+    }
+
+    /// Serializes the selected install location, target and options to JSON, to be loaded
+    /// with `import_config` elsewhere. Unlike what's installed, this is what should be installed.
+    pub fn export_config(&self) -> String {
+        let path_value = |path: &Path| JsonValue::String(path.to_string_lossy().into_owned());
+        let mut config = HashMap::new();
+        if let Some(install_dir) = &self.install_dir {
+            config.insert("install_dir".to_owned(), path_value(install_dir));
+        }
+        if let Some(version) = self.game_version {
+            config.insert("game_version".to_owned(), JsonValue::String(version.id().to_owned()));
+        }
+        config.insert("target".to_owned(), JsonValue::String(self.target.dll_name().to_owned()));
+        if let Some(custom_target) = &self.custom_target {
+            config.insert("custom_target".to_owned(), JsonValue::String(custom_target.clone()));
+        }
+        if let Some(steam_app_id) = self.steam_app_id {
+            config.insert("steam_app_id".to_owned(), JsonValue::Number(steam_app_id as f64));
+        }
+        if let Some(temp_dir) = &self.temp_dir {
+            config.insert("temp_dir".to_owned(), path_value(temp_dir));
+        }
+        config.insert("steam_strategy".to_owned(), JsonValue::String(self.steam_strategy.id().to_owned()));
+        config.insert("keep_exe_backup".to_owned(), JsonValue::Boolean(self.keep_exe_backup));
+        config.insert("vdf_audit_log".to_owned(), JsonValue::Boolean(self.vdf_audit_log));
+        config.insert(
+            "preserved_data".to_owned(),
+            JsonValue::Array(self.preserved_data.iter().map(|path| path_value(path)).collect())
+        );
+
+        JsonValue::Object(config).format().unwrap_or_default()
+    }
+
+    /// Loads a config written by `export_config`. The install dir must still contain the game.
+    pub fn import_config(&mut self, path: &Path) -> Result<(), Error> {
+        let invalid = || Error::InvalidConfig(path.to_path_buf());
+        let json = std::fs::read_to_string(path)?;
+        let JsonValue::Object(config) = json.parse::<JsonValue>().map_err(|_| invalid())? else {
+            return Err(invalid());
+        };
+        let string = |key: &str| match config.get(key) {
+            Some(JsonValue::String(s)) => Ok(Some(s.clone())),
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(_) => Err(invalid())
+        };
+
+        if let Some(target) = string("target")? {
+            self.target = *Target::VALUES.iter()
+                .find(|t| t.dll_name().eq_ignore_ascii_case(&target))
+                .ok_or_else(invalid)?;
+        }
+        self.custom_target = string("custom_target")?;
+        self.steam_app_id = match config.get("steam_app_id") {
+            Some(JsonValue::Number(id)) if id.fract() == 0.0 && *id >= 0.0 && *id <= u32::MAX as f64 => Some(*id as u32),
+            None | Some(JsonValue::Null) => None,
+            Some(_) => return Err(invalid())
+        };
+        self.temp_dir = string("temp_dir")?.map(PathBuf::from);
+        if let Some(strategy) = string("steam_strategy")? {
+            self.steam_strategy = SteamStrategy::from_id(&strategy).ok_or_else(invalid)?;
+        }
+        match config.get("keep_exe_backup") {
+            Some(JsonValue::Boolean(keep)) => self.keep_exe_backup = *keep,
+            None => {},
+            Some(_) => return Err(invalid())
+        }
+        match config.get("vdf_audit_log") {
+            Some(JsonValue::Boolean(log)) => self.vdf_audit_log = *log,
+            None => {},
+            Some(_) => return Err(invalid())
+        }
+        match config.get("preserved_data") {
+            Some(JsonValue::Array(paths)) => {
+                self.preserved_data = paths.iter()
+                    .map(|path| match path {
+                        JsonValue::String(path) => Ok(PathBuf::from(path)),
+                        _ => Err(invalid())
+                    })
+                    .collect::<Result<_, _>>()?;
+            },
+            None => {},
+            Some(_) => return Err(invalid())
+        }
+
+        let version = string("game_version")?
+            .map(|id| GameVersion::from_id(&id).ok_or_else(invalid))
+            .transpose()?;
+        if let Some(install_dir) = string("install_dir")? {
+            self.set_install_dir(PathBuf::from(install_dir))?;
+            if version.is_some_and(|version| Some(version) != self.game_version) {
+                return Err(Error::InvalidInstallDir);
+            }
+        } else if let Some(version) = version {
+            self.set_game_version(version);
+        }
+
+        Ok(())
+    }
+
+    fn steam_exe_path(install_dir: &Path) -> PathBuf {
+        install_dir.join(GameVersion::Steam.exe_name())
+    }
+
+    /// The Steam app manifest that holds the game's auto-update setting.
+    fn steam_manifest_path(&self) -> Option<PathBuf> {
+        let steamapps_path = find_steamapps_folder(self.install_dir.as_ref()?)?;
+        Some(steamapps_path.join(format!("appmanifest_{}.acf", self.steam_app_id()?)))
+    }
+
+    /// Where Cellar is installed for DotLocal installs, next to the target DLL.
+    fn get_cellar_path(&self) -> Option<PathBuf> {
+        Some(self.get_current_target_dir()?.join("apphelp.dll"))
+    }
+
     pub fn get_dest_plugin_path(&self) -> Option<PathBuf> {
         Some(self.install_dir.as_ref()?.join(format!("hachimi\\{}", self.target.dll_name())))
     }
 
     pub fn get_src_plugin_path(&self) -> Option<PathBuf> {
-        Some(self.install_dir.as_ref()?.join(format!("umamusume_Data\\Plugins\\x86_64\\{}", self.target.dll_name())))
+        self.get_src_plugin_path_for(self.target)
+    }
+
+    fn get_src_plugin_path_for(&self, target: Target) -> Option<PathBuf> {
+        let arch = self.game_architecture().unwrap_or(Architecture::X64);
+        Some(self.install_dir.as_ref()?.join(format!("umamusume_Data\\Plugins\\{}\\{}", arch.plugin_dir_name(), target.dll_name())))
     }
 }
 
@@ -649,6 +1973,11 @@ fn find_steamapps_folder(game_install_dir: &Path) -> Option<PathBuf> {
     None
 }
 
+/// The value of a `"key"\t\t"value"` line in a VDF file.
+fn vdf_line_value(line: &str) -> &str {
+    line.trim().rsplit('"').nth(1).unwrap_or_default()
+}
+
 impl Default for Installer {
     fn default() -> Installer {
         let mut installer = Self::new(Target::default(), None);
@@ -657,7 +1986,7 @@ impl Default for Installer {
     }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Target {
     UnityPlayer,
     CriManaVpx
@@ -683,13 +2012,113 @@ impl Default for Target {
     }
 }
 
+/// How Hachimi is installed for Steam (Japan).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SteamStrategy {
+    /// Patch the game executable and install the selected target.
+    #[default]
+    ExePatch,
+    /// Leave the executable untouched and always install as a DotLocal proxy DLL.
+    DotLocalProxy
+}
+
+impl SteamStrategy {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::ExePatch => "exe-patch",
+            Self::DotLocalProxy => "dotlocal-proxy"
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<SteamStrategy> {
+        match id {
+            "exe-patch" => Some(Self::ExePatch),
+            "dotlocal-proxy" => Some(Self::DotLocalProxy),
+            _ => None
+        }
+    }
+}
+
+/// Which targets to look at when checking what's installed. Each one means reading a DLL from disk.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TargetScope {
+    #[default]
+    All,
+    Only(Target)
+}
+
+impl TargetScope {
+    pub fn targets(&self) -> &[Target] {
+        match self {
+            Self::All => Target::VALUES,
+            Self::Only(target) => std::slice::from_ref(target)
+        }
+    }
+}
+
+/// Which known Steam (Japan) executable a fast fingerprint belongs to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FingerprintMatch {
+    Original,
+    Patched,
+    Unknown
+}
+
+/// How files are removed during uninstall.
 #[derive(Clone, Copy, Eq, PartialEq)]
-enum InstallMethod {
+pub enum DeleteMode {
+    /// Move files to the recycle bin so they can be recovered.
+    RecycleBin,
+    /// Delete files outright.
+    Permanent
+}
+
+impl DeleteMode {
+    pub fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        utils::clear_readonly(path)?;
+        match self {
+            Self::RecycleBin => utils::move_to_recycle_bin(path),
+            Self::Permanent => std::fs::remove_file(path)
+        }
+    }
+}
+
+impl Default for DeleteMode {
+    fn default() -> Self {
+        Self::RecycleBin
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct UninstallOptions {
+    pub delete_mode: DeleteMode,
+    /// Delete the target file even if it doesn't identify itself as Hachimi.
+    pub force: bool
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum InstallMethod {
     DotLocal,
     PluginShim,
     Direct,
 }
 
+impl InstallMethod {
+    const VALUES: &[Self] = &[
+        Self::DotLocal,
+        Self::PluginShim,
+        Self::Direct
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DotLocal => "DotLocal",
+            Self::PluginShim => "PluginShim",
+            Self::Direct => "Direct"
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct TargetVersionInfo {
     pub name: Option<String>,
@@ -710,6 +2139,193 @@ impl TargetVersionInfo {
     }
 }
 
+pub struct PatchEstimate {
+    pub size: u64,
+    pub seconds: u64
+}
+
+/// What was found at a target's path.
+#[derive(Debug)]
+pub enum TargetStatus {
+    Absent,
+    Present(TargetVersionInfo),
+    // There is a file, but it can't be identified. Holds the reason.
+    Unreadable(String)
+}
+
+impl TargetStatus {
+    pub fn is_present(&self) -> bool {
+        !matches!(self, TargetStatus::Absent)
+    }
+
+    /// Files that can't be identified get empty version info.
+    pub fn into_version_info(self) -> Option<TargetVersionInfo> {
+        match self {
+            TargetStatus::Absent => None,
+            TargetStatus::Present(version_info) => Some(version_info),
+            TargetStatus::Unreadable(_) => Some(TargetVersionInfo::default())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Architecture {
+    X86,
+    X64
+}
+
+impl Architecture {
+    fn from_machine(machine: u16) -> Option<Architecture> {
+        match machine {
+            pelite::image::IMAGE_FILE_MACHINE_I386 => Some(Self::X86),
+            pelite::image::IMAGE_FILE_MACHINE_AMD64 => Some(Self::X64),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X86 => "x86",
+            Self::X64 => "x64"
+        }
+    }
+
+    /// Unity's per-architecture plugin folder name.
+    pub fn plugin_dir_name(&self) -> &'static str {
+        match self {
+            Self::X86 => "x86",
+            Self::X64 => "x86_64"
+        }
+    }
+}
+
+/// How Hachimi was found to be installed, see `Installer::detect_existing_install_shape`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InstallShape {
+    NotInstalled,
+    Installed {
+        method: InstallMethod,
+        // The Steam (Japan) executable was patched and its backup is still there
+        exe_patched: bool
+    },
+    // Left over from installing with a different target or game version
+    Mixed(Vec<InstallMethod>)
+}
+
+impl InstallShape {
+    pub fn get_display_label(&self) -> String {
+        match self {
+            Self::NotInstalled => t!("installer.install_shape.not_installed"),
+            Self::Installed { method, exe_patched: false } => method.name().to_owned(),
+            Self::Installed { method, exe_patched: true } => t!("installer.install_shape.exe_patched", method = method.name()),
+            Self::Mixed(methods) => {
+                let names: Vec<&str> = methods.iter().map(|method| method.name()).collect();
+                t!("installer.install_shape.mixed", methods = names.join(", "))
+            }
+        }
+    }
+}
+
+/// See `Installer::effective_install_method`.
+pub struct EffectiveInstallMethod {
+    pub method: InstallMethod,
+    // False if it needs DotLocal redirection, which this system doesn't support
+    pub supported: bool,
+    // A target that works without DotLocal redirection, if it's unsupported and there is one
+    pub recommended_target: Option<Target>
+}
+
+impl EffectiveInstallMethod {
+    pub fn get_warning(&self) -> Option<String> {
+        if self.supported {
+            return None;
+        }
+        Some(match self.recommended_target {
+            Some(target) => t!("installer.dotlocal_unsupported_use_target", dll = target.dll_name()),
+            None => t!("installer.dotlocal_unsupported")
+        })
+    }
+}
+
+pub enum LoadLikelihood {
+    Yes,
+    No(String),
+    AfterReboot
+}
+
+pub struct CandidateDir {
+    pub version: GameVersion,
+    pub path: PathBuf,
+    pub architecture: Option<Architecture>,
+    pub hachimi: Option<TargetVersionInfo>
+}
+
+impl CandidateDir {
+    pub fn get_display_label(&self) -> String {
+        let mut label = format!("{} - {}", self.version.display_name(), self.path.display());
+        if let Some(architecture) = self.architecture {
+            label = format!("{} ({})", label, architecture.name());
+        }
+        match &self.hachimi {
+            Some(version_info) => {
+                let version = version_info.version.as_deref().unwrap_or("Unknown");
+                format!("{} - {}", label, t!("installer.hachimi_installed", version = version))
+            }
+            None => label
+        }
+    }
+}
+
+/// What the user still has to do after an uninstall for it to fully take effect,
+/// and what was deliberately left behind.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UninstallFollowUp {
+    pub restart_steam: bool,
+    // The Steam (Japan) executable's backup was kept, see `Installer::keep_exe_backup`
+    pub kept_exe_backup: bool
+}
+
+impl UninstallFollowUp {
+    pub fn get_message(&self) -> Option<String> {
+        let messages: Vec<String> = [
+            self.restart_steam.then(|| t!("installer.restart_steam_to_finish")),
+            self.kept_exe_backup.then(|| t!("installer.kept_exe_backup", file_name = GameVersion::Steam.exe_name()))
+        ]
+            .into_iter()
+            .flatten()
+            .collect();
+        (!messages.is_empty()).then(|| messages.join("\n\n"))
+    }
+}
+
+pub struct UninstallReport {
+    pub target: Target,
+    pub path: PathBuf,
+    pub result: Result<(), Error>
+}
+
+pub struct MigrateReport {
+    pub uninstall: Result<(), Error>,
+    pub install: Result<(), Error>
+}
+
+#[derive(Debug)]
+pub struct Conflict {
+    pub target: Target,
+    pub path: PathBuf,
+    pub version_info: TargetVersionInfo
+}
+
+impl Conflict {
+    pub fn get_display_label(&self) -> String {
+        let name = self.version_info.name.as_deref().unwrap_or("Unknown");
+        match &self.version_info.version {
+            Some(version) => format!("{} ({} {})", self.path.display(), name, version),
+            None => format!("{} ({})", self.path.display(), name)
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     NoInstallDir,
@@ -718,9 +2334,41 @@ pub enum Error {
     IoError(std::io::Error),
     RegistryValueError(registry::value::Error),
     VerificationError(String),
+    FileLocked(String, Vec<String>),
+    CorruptedPayload(String),
+    InvalidBundledDll(String),
+    UnknownGameVersion(Vec<String>),
+    GameVersionNotFound(GameVersion),
+    InvalidDllOverride(PathBuf),
+    UntrustedPayload(PathBuf),
+    InvalidConfig(PathBuf),
+    InvalidSteamDir(PathBuf),
+    InstallDirNotWritable(PathBuf),
+    PatchProducedInvalidPe(String),
+    CustomTargetCollision(String),
+    RefusingToDeleteForeignFile(String),
+    BackupsInUse,
+    NotElevated,
+    Cancelled,
+    InsufficientDiskSpace(u64, u64),
+    ArchitectureMismatch(Architecture, Architecture),
     Generic(Box<dyn std::error::Error + Send + Sync>),
 }
 
+impl Error {
+    /// Converts an I/O error on `path` into `FileLocked` if another process has the file open.
+    pub fn from_locked_file(path: &Path, e: std::io::Error) -> Error {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            let processes = utils::get_locking_processes(path);
+            if !processes.is_empty() {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                return Error::FileLocked(file_name, processes);
+            }
+        }
+        Error::IoError(e)
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -730,6 +2378,39 @@ impl std::fmt::Display for Error {
             Error::IoError(e) => write!(f, "{}", t!("error.io_error", error = e)),
             Error::RegistryValueError(e) => write!(f, "{}", t!("error.registry_value_error", error = e)),
             Error::VerificationError(e) => write!(f, "{}", t!("error.verification_error", error = e)),
+            Error::FileLocked(file_name, processes) => write!(f, "{}", t!("error.file_locked", file_name = file_name, processes = processes.join(", "))),
+            Error::CorruptedPayload(name) => write!(f, "{}", t!("error.corrupted_payload", name = name)),
+            Error::InvalidBundledDll(name) => write!(f, "{}", t!("error.invalid_bundled_dll", name = name)),
+            Error::UnknownGameVersion(exes) => {
+                let found = if exes.is_empty() {
+                    t!("error.no_exes_found")
+                } else {
+                    exes.join(", ")
+                };
+                write!(f, "{}", t!("error.unknown_game_version", found = found))
+            },
+            Error::GameVersionNotFound(version) => write!(f, "{}", t!("error.game_version_not_found", version = version.display_name())),
+            Error::InvalidDllOverride(path) => write!(f, "{}", t!("error.invalid_dll_override", path = path.display())),
+            Error::UntrustedPayload(path) => write!(f, "{}", t!("error.untrusted_payload", path = path.display())),
+            Error::InvalidConfig(path) => write!(f, "{}", t!("error.invalid_config", path = path.display())),
+            Error::InvalidSteamDir(path) => write!(f, "{}", t!("error.invalid_steam_dir", path = path.display())),
+            Error::InstallDirNotWritable(path) => write!(f, "{}", t!("error.install_dir_not_writable", path = path.display())),
+            Error::PatchProducedInvalidPe(file_name) => write!(f, "{}", t!("error.patch_produced_invalid_pe", file_name = file_name)),
+            Error::CustomTargetCollision(name) => write!(f, "{}", t!("error.custom_target_collision", name = name)),
+            Error::RefusingToDeleteForeignFile(name) => write!(f, "{}", t!("error.refusing_to_delete_foreign_file", name = name)),
+            Error::BackupsInUse => write!(f, "{}", t!("error.backups_in_use")),
+            Error::NotElevated => write!(f, "{}", t!("error.not_elevated")),
+            Error::Cancelled => write!(f, "{}", t!("error.cancelled")),
+            Error::InsufficientDiskSpace(required, available) => write!(f, "{}", t!(
+                "error.insufficient_disk_space",
+                required = utils::format_size(*required),
+                available = utils::format_size(*available)
+            )),
+            Error::ArchitectureMismatch(dll_arch, game_arch) => write!(f, "{}", t!(
+                "error.architecture_mismatch",
+                dll_arch = dll_arch.name(),
+                game_arch = game_arch.name()
+            )),
             Error::Generic(e) => write!(f, "{}", t!("error.generic", error = e)),
         }
     }
@@ -746,3 +2427,43 @@ impl From<registry::value::Error> for Error {
         Error::RegistryValueError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_game_dir(version: GameVersion) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(version.exe_name()), b"").unwrap();
+        dir
+    }
+
+    #[test]
+    fn set_install_dir_keeps_other_versions_dirs() {
+        let dmm_dir = fake_game_dir(GameVersion::DMM);
+        let steam_dir = fake_game_dir(GameVersion::Steam);
+        let steam_global_dir = fake_game_dir(GameVersion::SteamGlobal);
+        let override_dir = fake_game_dir(GameVersion::Steam);
+
+        // Stands in for detect_install_dirs, which would look at the real system
+        let mut installer = Installer::new(Target::default(), None);
+        installer.dmm_install_dir = Some(dmm_dir.path().to_path_buf());
+        installer.steam_install_dir = Some(steam_dir.path().to_path_buf());
+        installer.steam_global_install_dir = Some(steam_global_dir.path().to_path_buf());
+        for (dir, version) in [
+            (&dmm_dir, GameVersion::DMM),
+            (&steam_dir, GameVersion::Steam),
+            (&steam_global_dir, GameVersion::SteamGlobal)
+        ] {
+            assert_eq!(installer.detect_version_from_dir(dir.path()), Some(version));
+        }
+
+        installer.set_install_dir(override_dir.path().to_path_buf()).unwrap();
+
+        assert_eq!(installer.game_version(), Some(GameVersion::Steam));
+        assert_eq!(installer.install_dir().map(PathBuf::as_path), Some(override_dir.path()));
+        assert_eq!(installer.steam_install_dir().map(PathBuf::as_path), Some(override_dir.path()));
+        assert_eq!(installer.dmm_install_dir().map(PathBuf::as_path), Some(dmm_dir.path()));
+        assert_eq!(installer.steam_global_install_dir().map(PathBuf::as_path), Some(steam_global_dir.path()));
+    }
+}
@@ -0,0 +1,219 @@
+//! Drives the installer from another program when started with `--ipc`.
+//!
+//! Every line on stdin is a JSON command such as `{"command": "install", "post_install": true}`,
+//! and every line written to stdout is a JSON event such as `{"event": "progress", "stage": "...", "percent": 50}`.
+//! The first event is always `hello`, which carries `PROTOCOL_VERSION`.
+
+use std::{cell::RefCell, collections::HashMap, io::{BufRead, Write}, path::PathBuf};
+
+use tinyjson::JsonValue;
+
+use crate::{
+    installer::{DeleteMode, Installer, Target, TargetStatus, UninstallOptions},
+    prompt::{Prompter, RetryCancel}
+};
+
+/// Incremented whenever a command or event changes in a way that breaks existing frontends.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+type Object = HashMap<String, JsonValue>;
+
+pub fn wants_ipc() -> bool {
+    std::env::args_os()
+        .take_while(|arg| arg != "--")
+        .any(|arg| arg == "--ipc")
+}
+
+fn emit(event: &str, fields: Vec<(&str, JsonValue)>) {
+    let mut object: Object = fields.into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect();
+    object.insert("event".to_owned(), JsonValue::String(event.to_owned()));
+    let Ok(line) = JsonValue::Object(object).stringify() else {
+        return;
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    _ = writeln!(stdout, "{}", line);
+    _ = stdout.flush();
+}
+
+fn string(s: impl Into<String>) -> JsonValue {
+    JsonValue::String(s.into())
+}
+
+fn optional_string(s: Option<impl Into<String>>) -> JsonValue {
+    s.map_or(JsonValue::Null, string)
+}
+
+/// Reads the next command, skipping lines that aren't one. Returns `None` once stdin is closed.
+fn read_command() -> Option<(String, Object)> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if std::io::stdin().lock().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match line.trim().parse::<JsonValue>() {
+            Ok(JsonValue::Object(mut object)) => match object.remove("command") {
+                Some(JsonValue::String(command)) => return Some((command, object)),
+                _ => emit("error", vec![("message", string("Missing \"command\""))])
+            },
+            Ok(_) => emit("error", vec![("message", string("Expected an object"))]),
+            Err(e) => emit("error", vec![("message", string(e.to_string()))])
+        }
+    }
+}
+
+fn get_bool(object: &Object, key: &str) -> bool {
+    matches!(object.get(key), Some(JsonValue::Boolean(true)))
+}
+
+fn get_string<'a>(object: &'a Object, key: &str) -> Option<&'a str> {
+    match object.get(key) {
+        Some(JsonValue::String(s)) => Some(s),
+        _ => None
+    }
+}
+
+/// Forwards questions and messages to the frontend. Questions block until it sends
+/// `{"command": "answer", "value": true}`, and are declined if stdin is closed.
+struct IpcPrompter;
+
+impl IpcPrompter {
+    fn ask(&self, kind: &str, title: &str, body: &str) -> bool {
+        emit("question", vec![("kind", string(kind)), ("title", string(title)), ("body", string(body))]);
+        while let Some((command, args)) = read_command() {
+            if command == "answer" {
+                return get_bool(&args, "value");
+            }
+            emit("error", vec![("message", string("Expected an answer to the question"))]);
+        }
+        false
+    }
+
+    fn message(&self, level: &str, title: &str, body: &str) {
+        emit("message", vec![("level", string(level)), ("title", string(title)), ("body", string(body))]);
+    }
+}
+
+impl Prompter for IpcPrompter {
+    fn wants_optional_prompts(&self) -> bool {
+        true
+    }
+
+    fn confirm(&self, title: &str, body: &str) -> bool {
+        self.ask("confirm", title, body)
+    }
+
+    fn retry_cancel(&self, title: &str, body: &str) -> RetryCancel {
+        if self.ask("retry_cancel", title, body) {
+            RetryCancel::Retry
+        } else {
+            RetryCancel::Cancel
+        }
+    }
+
+    fn info(&self, title: &str, body: &str) {
+        self.message("info", title, body);
+    }
+
+    fn warn(&self, title: &str, body: &str) {
+        self.message("warning", title, body);
+    }
+
+    fn error(&self, title: &str, body: &str) {
+        self.message("error", title, body);
+    }
+}
+
+fn emit_status(installer: &Installer) {
+    let installed_version = match installer.get_target_status(installer.target) {
+        TargetStatus::Present(info) => optional_string(info.version),
+        _ => JsonValue::Null
+    };
+    emit("status", vec![
+        ("install_dir", optional_string(installer.install_dir().map(|dir| dir.to_string_lossy().into_owned()))),
+        ("game_version", optional_string(installer.game_version().map(|version| version.id()))),
+        ("target", string(installer.target.dll_name())),
+        ("installed_version", installed_version)
+    ]);
+}
+
+fn run_command(installer: &mut Installer, command: &str, args: &Object) -> Result<(), String> {
+    match command {
+        "detect" => installer.detect_install_dir(),
+        "select-dir" => {
+            let path = get_string(args, "path").ok_or("Missing \"path\"")?;
+            installer.set_install_dir(PathBuf::from(path)).map_err(|e| e.to_string())?;
+        },
+        "set-target" => {
+            let dll_name = get_string(args, "target").ok_or("Missing \"target\"")?;
+            installer.target = *Target::VALUES.iter()
+                .find(|target| target.dll_name().eq_ignore_ascii_case(dll_name))
+                .ok_or("Unknown target")?;
+        },
+        "install" => {
+            if get_bool(args, "pre_install") {
+                installer.pre_install().map_err(|e| e.to_string())?;
+            }
+            installer.install().map_err(|e| e.to_string())?;
+            if get_bool(args, "post_install") {
+                installer.post_install().map_err(|e| e.to_string())?;
+            }
+        },
+        "uninstall" => {
+            let options = UninstallOptions {
+                delete_mode: if get_bool(args, "permanent") { DeleteMode::Permanent } else { DeleteMode::RecycleBin },
+                force: get_bool(args, "force")
+            };
+            installer.uninstall(options).map_err(|e| e.to_string())?;
+        },
+        "status" => {},
+        _ => return Err(format!("Unknown command \"{}\"", command))
+    }
+    Ok(())
+}
+
+pub fn run() {
+    let mut installer = Installer::default();
+    installer.prompter = Box::new(IpcPrompter);
+    // Hashing reports progress for every chunk read, so only changes are sent
+    let last = RefCell::new((String::new(), u8::MAX));
+    installer.progress = Some(Box::new(move |stage, percent| {
+        let mut last = last.borrow_mut();
+        if last.0 == stage && last.1 == percent {
+            return;
+        }
+        *last = (stage.to_owned(), percent);
+        emit("progress", vec![("stage", string(stage)), ("percent", JsonValue::Number(percent as f64))]);
+    }));
+
+    let hello = || emit("hello", vec![
+        ("protocol", JsonValue::Number(PROTOCOL_VERSION as f64)),
+        ("version", string(env!("CARGO_PKG_VERSION")))
+    ]);
+    hello();
+
+    while let Some((command, args)) = read_command() {
+        match command.as_str() {
+            "hello" => hello(),
+            "quit" => break,
+            _ => match run_command(&mut installer, &command, &args) {
+                Ok(()) => {
+                    emit("result", vec![("command", string(&command)), ("ok", JsonValue::Boolean(true))]);
+                    emit_status(&installer);
+                },
+                Err(e) => emit("result", vec![
+                    ("command", string(&command)),
+                    ("ok", JsonValue::Boolean(false)),
+                    ("error", string(e))
+                ])
+            }
+        }
+    }
+}
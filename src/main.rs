@@ -2,9 +2,13 @@
 
 mod i18n;
 mod installer;
+mod ipc;
+mod payload;
+mod prompt;
 mod resource;
 mod utils;
 mod cli;
+mod crash;
 mod gui;
 mod updater;
 
@@ -15,7 +19,16 @@ pub use crate::i18n::_rust_i18n_translate;
 extern crate include_bytes_zstd;
 
 fn main() -> Result<(), installer::Error> {
-    let update_status = updater::run_update_check();
+    crash::install_panic_hook(cli::wants_minidump());
+
+    // Driven by another program over stdin and stdout, which must not be mixed with update output
+    if ipc::wants_ipc() {
+        i18n::init_locale();
+        ipc::run();
+        return Ok(());
+    }
+
+    let update_status = updater::run_update_check(cli::update_channel());
 
     // Set language by system language
     i18n::init_locale();
@@ -0,0 +1,176 @@
+use std::{borrow::Cow, path::PathBuf};
+
+use tinyjson::JsonValue;
+
+use crate::{installer::Error, utils};
+
+// Checksums of the embedded files, computed by build.rs
+const HACHIMI_DLL_SHA256: &str = env!("HACHIMI_DLL_SHA256");
+const CELLAR_DLL_SHA256: &str = env!("CELLAR_DLL_SHA256");
+const STEAM_PATCH_SHA256: &str = env!("STEAM_PATCH_SHA256");
+
+// Steam (Japan) executable before and after applying the embedded patch
+const STEAM_ORIGINAL_EXE_SHA256: &str = "11015e76281aeed9c31edaecdcbed73013c5b26d4b024a89b02563f250ae61a7";
+const STEAM_PATCHED_EXE_SHA256: &str = "9d6955463a0a509a2355d2227a4ee9ef0ca5da3f0f908b0c846a1e3c218cb703";
+
+// Their `utils::fingerprint_file` fingerprints. The original executable isn't part of the repo,
+// so these can only be given when building
+const STEAM_ORIGINAL_EXE_FINGERPRINT: Option<&str> = option_env!("STEAM_ORIGINAL_EXE_FINGERPRINT");
+const STEAM_PATCHED_EXE_FINGERPRINT: Option<&str> = option_env!("STEAM_PATCHED_EXE_FINGERPRINT");
+
+const SIDECAR_FILE_NAME: &str = "patches.json";
+
+fn verify(name: &str, data: &[u8], expected_hash: &str) -> Result<(), Error> {
+    if utils::sha256_hex(data) == expected_hash {
+        Ok(())
+    } else {
+        Err(Error::CorruptedPayload(name.to_owned()))
+    }
+}
+
+/// Catches installers built with a placeholder instead of the real DLL, which would pass `verify`
+/// since its checksum is computed from whatever file was there at build time.
+fn check_bundled_dll(name: &str, data: &[u8]) -> Result<(), Error> {
+    if pelite::PeFile::from_bytes(data).is_err() || utils::read_pe_version_info(data).is_none() {
+        return Err(Error::InvalidBundledDll(name.to_owned()));
+    }
+    Ok(())
+}
+
+pub fn hachimi_dll() -> Result<Cow<'static, [u8]>, Error> {
+    #[cfg(feature = "compress_dll")]
+    let data = Cow::Owned(include_bytes_zstd!("hachimi.dll", 19));
+
+    #[cfg(not(feature = "compress_dll"))]
+    let data = Cow::Borrowed(&include_bytes!("../hachimi.dll")[..]);
+
+    verify("hachimi.dll", &data, HACHIMI_DLL_SHA256)?;
+    check_bundled_dll("hachimi.dll", &data)?;
+    Ok(data)
+}
+
+pub fn cellar_dll() -> Result<Cow<'static, [u8]>, Error> {
+    #[cfg(feature = "compress_dll")]
+    let data = Cow::Owned(include_bytes_zstd!("cellar.dll", 19));
+
+    #[cfg(not(feature = "compress_dll"))]
+    let data = Cow::Borrowed(&include_bytes!("../cellar.dll")[..]);
+
+    verify("cellar.dll", &data, CELLAR_DLL_SHA256)?;
+    check_bundled_dll("cellar.dll", &data)?;
+    Ok(data)
+}
+
+/// A zstd-compressed bsdiff patch for the Steam (Japan) executable.
+pub struct SteamPatch {
+    pub original_hash: String,
+    pub patched_hash: String,
+    // Fast fingerprints of the same executables, if known
+    pub original_fingerprint: Option<String>,
+    pub patched_fingerprint: Option<String>,
+    pub data: Cow<'static, [u8]>
+}
+
+fn steam_patch() -> Result<SteamPatch, Error> {
+    let data = &include_bytes!("../umamusume.patch.zst")[..];
+    verify("umamusume.patch.zst", data, STEAM_PATCH_SHA256)?;
+    Ok(SteamPatch {
+        original_hash: STEAM_ORIGINAL_EXE_SHA256.to_owned(),
+        patched_hash: STEAM_PATCHED_EXE_SHA256.to_owned(),
+        original_fingerprint: STEAM_ORIGINAL_EXE_FINGERPRINT.map(str::to_ascii_lowercase),
+        patched_fingerprint: STEAM_PATCHED_EXE_FINGERPRINT.map(str::to_ascii_lowercase),
+        data: Cow::Borrowed(data)
+    })
+}
+
+fn is_sha256_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Reads the patches listed in patches.json next to the installer, e.g.
+/// `{ "steam": [{ "original": "<sha256>", "patched": "<sha256>", "patch": "umamusume.patch.zst" }] }`,
+/// optionally with `original_fingerprint` and `patched_fingerprint`.
+/// The whole file is ignored if any entry is malformed or its patch file can't be read.
+fn read_sidecar_patches() -> Option<Vec<SteamPatch>> {
+    let path = std::env::current_exe().ok()?.with_file_name(SIDECAR_FILE_NAME);
+    let json = std::fs::read_to_string(&path).ok()?;
+    let JsonValue::Object(root) = json.parse().ok()? else {
+        return None;
+    };
+    let JsonValue::Array(entries) = root.get("steam")? else {
+        return None;
+    };
+
+    entries.iter()
+        .map(|entry| {
+            let JsonValue::Object(entry) = entry else {
+                return None;
+            };
+            let (
+                Some(JsonValue::String(original_hash)),
+                Some(JsonValue::String(patched_hash)),
+                Some(JsonValue::String(patch_file))
+            ) = (entry.get("original"), entry.get("patched"), entry.get("patch")) else {
+                return None;
+            };
+            if !is_sha256_hex(original_hash) || !is_sha256_hex(patched_hash) {
+                return None;
+            }
+            let fingerprint = |key: &str| match entry.get(key) {
+                None => Some(None),
+                Some(JsonValue::String(fingerprint)) if is_sha256_hex(fingerprint) => Some(Some(fingerprint.to_ascii_lowercase())),
+                Some(_) => None
+            };
+            let original_fingerprint = fingerprint("original_fingerprint")?;
+            let patched_fingerprint = fingerprint("patched_fingerprint")?;
+
+            let data = std::fs::read(path.with_file_name(patch_file)).ok()?;
+            Some(SteamPatch {
+                original_hash: original_hash.to_ascii_lowercase(),
+                patched_hash: patched_hash.to_ascii_lowercase(),
+                original_fingerprint,
+                patched_fingerprint,
+                data: Cow::Owned(data)
+            })
+        })
+        .collect()
+}
+
+/// Patches from the sidecar file, if there is a valid one, followed by the embedded patch.
+pub fn steam_patches() -> Result<Vec<SteamPatch>, Error> {
+    let mut patches = read_sidecar_patches().unwrap_or_default();
+    patches.push(steam_patch()?);
+    Ok(patches)
+}
+
+/// A DLL on disk to be installed instead of the bundled one.
+pub struct PayloadOverride {
+    pub path: PathBuf,
+    // If set, the file is rejected unless its SHA-256 matches
+    pub sha256: Option<String>
+}
+
+/// Reads an override DLL, checking it against the pinned hash if there is one.
+/// If `expected_name` is set, the DLL's ProductName must match it.
+pub fn read_override(payload_override: &PayloadOverride, expected_name: Option<&str>) -> Result<Vec<u8>, Error> {
+    let path = &payload_override.path;
+    let data = std::fs::read(path)?;
+    if let Some(expected_hash) = &payload_override.sha256 {
+        if !utils::sha256_hex(&data).eq_ignore_ascii_case(expected_hash) {
+            return Err(Error::UntrustedPayload(path.to_path_buf()));
+        }
+    }
+    if pelite::PeFile::from_bytes(&data).is_err() {
+        return Err(Error::InvalidDllOverride(path.to_path_buf()));
+    }
+
+    if let Some(expected_name) = expected_name {
+        let name = utils::read_pe_version_info(&data)
+            .and_then(|version_info| utils::read_version_string(version_info, "ProductName"));
+        if name.as_deref() != Some(expected_name) {
+            return Err(Error::InvalidDllOverride(path.to_path_buf()));
+        }
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,95 @@
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::HWND,
+        UI::WindowsAndMessaging::{
+            MessageBoxW, IDRETRY, IDYES, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONQUESTION, MB_ICONWARNING,
+            MB_OK, MB_RETRYCANCEL, MB_YESNO, MESSAGEBOX_RESULT, MESSAGEBOX_STYLE
+        }
+    }
+};
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum RetryCancel {
+    Retry,
+    Cancel
+}
+
+/// Asks the user questions and shows messages on behalf of the installer.
+pub trait Prompter {
+    /// Whether optional prompts, such as recommendations, should be shown at all.
+    fn wants_optional_prompts(&self) -> bool;
+    fn confirm(&self, title: &str, body: &str) -> bool;
+    fn retry_cancel(&self, title: &str, body: &str) -> RetryCancel;
+    fn info(&self, title: &str, body: &str);
+    fn warn(&self, title: &str, body: &str);
+    fn error(&self, title: &str, body: &str);
+}
+
+pub struct MessageBoxPrompter {
+    owner: Option<HWND>
+}
+
+impl MessageBoxPrompter {
+    /// Without an owner window (CLI mode), optional prompts are skipped.
+    pub fn new(owner: Option<HWND>) -> MessageBoxPrompter {
+        MessageBoxPrompter { owner }
+    }
+
+    fn show(&self, title: &str, body: &str, style: MESSAGEBOX_STYLE) -> MESSAGEBOX_RESULT {
+        unsafe { MessageBoxW(self.owner.as_ref(), &HSTRING::from(body), &HSTRING::from(title), style) }
+    }
+}
+
+impl Prompter for MessageBoxPrompter {
+    fn wants_optional_prompts(&self) -> bool {
+        self.owner.is_some()
+    }
+
+    fn confirm(&self, title: &str, body: &str) -> bool {
+        self.show(title, body, MB_ICONQUESTION | MB_YESNO) == IDYES
+    }
+
+    fn retry_cancel(&self, title: &str, body: &str) -> RetryCancel {
+        if self.show(title, body, MB_ICONINFORMATION | MB_RETRYCANCEL) == IDRETRY {
+            RetryCancel::Retry
+        } else {
+            RetryCancel::Cancel
+        }
+    }
+
+    fn info(&self, title: &str, body: &str) {
+        self.show(title, body, MB_ICONINFORMATION | MB_OK);
+    }
+
+    fn warn(&self, title: &str, body: &str) {
+        self.show(title, body, MB_ICONWARNING | MB_OK);
+    }
+
+    fn error(&self, title: &str, body: &str) {
+        self.show(title, body, MB_ICONERROR | MB_OK);
+    }
+}
+
+/// Shows nothing and declines every question, for unattended installs.
+pub struct NoopPrompter;
+
+impl Prompter for NoopPrompter {
+    fn wants_optional_prompts(&self) -> bool {
+        false
+    }
+
+    fn confirm(&self, _title: &str, _body: &str) -> bool {
+        false
+    }
+
+    fn retry_cancel(&self, _title: &str, _body: &str) -> RetryCancel {
+        RetryCancel::Cancel
+    }
+
+    fn info(&self, _title: &str, _body: &str) {}
+
+    fn warn(&self, _title: &str, _body: &str) {}
+
+    fn error(&self, _title: &str, _body: &str) {}
+}
@@ -1,21 +1,185 @@
 use chrono::{DateTime, Utc};
 use std::env;
 use std::fs::{self, File};
-use reqwest::header::ACCEPT;
+use std::time::Duration;
+use reqwest::{blocking::{Client, Response}, header::{ACCEPT, RETRY_AFTER}, StatusCode};
 use tempfile::Builder;
+use tinyjson::JsonValue;
 use crate::i18n::t;
 
 #[derive(Debug)]
 pub enum UpdateStatus {
-    Updated(String),
+    Updated(UpdateInfo),
     NotNeeded,
     Failed(String),
     Disabled,
 }
 
-const RELEASE_TAG: &str = "nightly";
+#[derive(Debug)]
+pub struct UpdateInfo {
+    pub date: String,
+    pub prerelease: bool,
+}
+
+/// Which release the installer updates itself to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UpdateChannel {
+    /// The release tagged "nightly".
+    #[default]
+    Nightly,
+    /// The latest release that isn't a prerelease.
+    Stable,
+    /// The newest release, including prereleases.
+    Prerelease,
+}
 
-pub fn run_update_check() -> UpdateStatus {
+impl UpdateChannel {
+    pub fn from_id(id: &str) -> Option<UpdateChannel> {
+        match id {
+            "nightly" => Some(UpdateChannel::Nightly),
+            "stable" => Some(UpdateChannel::Stable),
+            "prerelease" => Some(UpdateChannel::Prerelease),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    Network(String),
+    // When the limit resets, if GitHub said so
+    RateLimited(Option<DateTime<Utc>>),
+    ParseFailed(String),
+}
+
+impl std::fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateError::Network(e) => write!(f, "{}", t!("details.update_error.network", error = e)),
+            UpdateError::RateLimited(Some(reset)) => write!(f, "{}", t!(
+                "details.update_error.rate_limited_until",
+                time = reset.with_timezone(&chrono::Local).format("%H:%M")
+            )),
+            UpdateError::RateLimited(None) => write!(f, "{}", t!("details.update_error.rate_limited")),
+            UpdateError::ParseFailed(e) => write!(f, "{}", t!("details.update_error.parse_failed", error = e)),
+        }
+    }
+}
+
+impl From<reqwest::Error> for UpdateError {
+    fn from(e: reqwest::Error) -> Self {
+        UpdateError::Network(e.to_string())
+    }
+}
+
+struct ReleaseAsset {
+    name: String,
+    download_url: String,
+}
+
+struct Release {
+    date: String,
+    prerelease: bool,
+    assets: Vec<ReleaseAsset>,
+}
+
+const NIGHTLY_TAG: &str = "nightly";
+
+// Checking for updates happens before the UI shows up, so give up quickly when offline
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn http_client(timeout: Duration) -> Result<Client, UpdateError> {
+    Ok(Client::builder()
+        .user_agent("hachimi_installer")
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(timeout)
+        .build()?)
+}
+
+/// Turns GitHub's rate limit responses into `RateLimited`, and other failures into `Network`.
+fn check_response(resp: Response) -> Result<Response, UpdateError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<i64>().ok());
+    let rate_limited = status == StatusCode::TOO_MANY_REQUESTS ||
+        (status == StatusCode::FORBIDDEN && header("x-ratelimit-remaining") == Some(0));
+    if rate_limited {
+        let reset = header("x-ratelimit-reset")
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .or_else(|| header(RETRY_AFTER.as_str()).map(|secs| Utc::now() + chrono::Duration::seconds(secs)));
+        return Err(UpdateError::RateLimited(reset));
+    }
+
+    Err(UpdateError::Network(format!("HTTP {}", status)))
+}
+
+fn parse_release(json: &JsonValue) -> Result<Release, UpdateError> {
+    let JsonValue::Object(release) = json else {
+        return Err(UpdateError::ParseFailed("release is not an object".to_owned()));
+    };
+    let date = match release.get("created_at") {
+        Some(JsonValue::String(date)) => date.clone(),
+        _ => String::new(),
+    };
+    let prerelease = matches!(release.get("prerelease"), Some(JsonValue::Boolean(true)));
+    let Some(JsonValue::Array(assets)) = release.get("assets") else {
+        return Err(UpdateError::ParseFailed("release has no assets array".to_owned()));
+    };
+    let assets = assets.iter()
+        .filter_map(|asset| {
+            let JsonValue::Object(asset) = asset else {
+                return None;
+            };
+            let (Some(JsonValue::String(name)), Some(JsonValue::String(url))) = (asset.get("name"), asset.get("url")) else {
+                return None;
+            };
+            Some(ReleaseAsset { name: name.clone(), download_url: url.clone() })
+        })
+        .collect();
+
+    Ok(Release { date, prerelease, assets })
+}
+
+/// Returns `None` if there's no release on the channel.
+fn fetch_release(repo_owner: &str, repo_name: &str, channel: UpdateChannel) -> Result<Option<Release>, UpdateError> {
+    let path = match channel {
+        UpdateChannel::Nightly => format!("releases/tags/{}", NIGHTLY_TAG),
+        UpdateChannel::Stable => "releases/latest".to_owned(),
+        // Newest first
+        UpdateChannel::Prerelease => "releases?per_page=1".to_owned(),
+    };
+    let url = format!("https://api.github.com/repos/{}/{}/{}", repo_owner, repo_name, path);
+    let resp = http_client(API_TIMEOUT)?
+        .get(url)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let body = check_response(resp)?.text()?;
+
+    let json = body.parse::<JsonValue>().map_err(|e| UpdateError::ParseFailed(e.to_string()))?;
+    match &json {
+        JsonValue::Array(releases) => releases.first().map(parse_release).transpose(),
+        _ => parse_release(&json).map(Some),
+    }
+}
+
+fn download_asset(asset: &ReleaseAsset, mut dest: File) -> Result<(), UpdateError> {
+    let resp = http_client(DOWNLOAD_TIMEOUT)?
+        .get(&asset.download_url)
+        .header(ACCEPT, "application/octet-stream")
+        .send()?;
+    check_response(resp)?.copy_to(&mut dest)?;
+    Ok(())
+}
+
+pub fn run_update_check(channel: UpdateChannel) -> UpdateStatus {
     let repo_owner = option_env!("REPO_OWNER");
     let repo_name = option_env!("REPO_NAME");
 
@@ -40,31 +204,23 @@ pub fn run_update_check() -> UpdateStatus {
         Err(e) => return UpdateStatus::Failed(t!("details.update_error.exe_modtime", error = e.to_string())),
     };
 
-    let releases = match self_update::backends::github::ReleaseList::configure()
-        .repo_owner(repo_owner)
-        .repo_name(repo_name)
-        .build()
-        .and_then(|builder| builder.fetch())
-    {
-        Ok(releases) => releases,
+    let release = match fetch_release(repo_owner, repo_name, channel) {
+        Ok(Some(release)) => release,
+        Ok(None) if channel == UpdateChannel::Nightly => return UpdateStatus::Failed(t!("details.update_error.no_release_tag", tag = NIGHTLY_TAG)),
+        Ok(None) => return UpdateStatus::Failed(t!("details.update_error.no_release")),
         Err(e) => return UpdateStatus::Failed(t!("details.update_error.fetch_releases", error = e.to_string())),
     };
 
-    let nightly_release = match releases.iter().find(|r| r.version == RELEASE_TAG) {
-        Some(release) => release,
-        None => return UpdateStatus::Failed(t!("details.update_error.no_release_tag", tag = RELEASE_TAG)),
-    };
-
-    let asset = match nightly_release.assets.iter().find(|a| a.name == bin_name) {
+    let asset = match release.assets.iter().find(|a| a.name == bin_name) {
         Some(asset) => asset,
-        None => return UpdateStatus::Failed(t!("details.update_error.no_asset", asset_name = bin_name, tag = RELEASE_TAG)),
+        None => return UpdateStatus::Failed(t!("details.update_error.no_asset", asset_name = bin_name)),
     };
 
-    if nightly_release.date.is_empty() {
+    if release.date.is_empty() {
         return UpdateStatus::Failed(t!("details.update_error.no_release_date"));
     }
 
-    let remote_published_at = match DateTime::parse_from_rfc3339(&nightly_release.date) {
+    let remote_published_at = match DateTime::parse_from_rfc3339(&release.date) {
         Ok(dt) => dt.with_timezone(&Utc),
         Err(e) => return UpdateStatus::Failed(t!("details.update_error.parse_timestamp", error = e.to_string())),
     };
@@ -82,19 +238,18 @@ pub fn run_update_check() -> UpdateStatus {
             Err(e) => return UpdateStatus::Failed(t!("details.update_error.temp_file", error = e.to_string())),
         };
 
-        match self_update::Download::from_url(&asset.download_url)
-            .set_header(ACCEPT, "application/octet-stream".parse().unwrap())
-            .show_progress(true)
-            .download_to(new_exe_file) {
-                Ok(_) => (),
-                Err(e) => return UpdateStatus::Failed(t!("details.update_error.download", error = e.to_string())),
+        if let Err(e) = download_asset(asset, new_exe_file) {
+            return UpdateStatus::Failed(t!("details.update_error.download", error = e.to_string()));
         }
 
         match self_update::self_replace::self_replace(&new_exe_path) {
-            Ok(_) => UpdateStatus::Updated(remote_published_at.to_rfc2822()),
+            Ok(_) => UpdateStatus::Updated(UpdateInfo {
+                date: remote_published_at.to_rfc2822(),
+                prerelease: release.prerelease,
+            }),
             Err(e) => UpdateStatus::Failed(t!("details.update_error.self_replace", error = e.to_string())),
         }
     } else {
         UpdateStatus::NotNeeded
     }
-}
\ No newline at end of file
+}
@@ -1,30 +1,56 @@
 use sha2::{Digest, Sha256};
-use std::{ffi::{CStr, OsString, CString}, os::windows::ffi::OsStringExt, path::{Path, PathBuf}, fs::File, io::{Read, Write}};
+use std::{collections::HashMap, ffi::{CStr, OsStr, OsString}, os::windows::{ffi::{OsStrExt, OsStringExt}, fs::MetadataExt}, path::{Path, PathBuf}, fs::File, io::{Read, Seek, SeekFrom, Write}, sync::atomic::{AtomicBool, Ordering}};
 use crate::i18n::{t};
 
-use pelite::resources::version_info::VersionInfo;
+use pelite::resources::version_info::{Language, VersionInfo};
 use windows::{
-    core::HSTRING,
+    core::{w, HSTRING, PCWSTR, PWSTR},
     Win32::{
-        Foundation::{HWND, MAX_PATH, RECT},
+        Foundation::{CloseHandle, GetLastError, ERROR_MORE_DATA, ERROR_SUCCESS, FILETIME, HWND, MAX_PATH, RECT},
         System::{
-            Com::{CoCreateInstance, CLSCTX_INPROC_SERVER},
+            Com::{CoCreateInstance, CoTaskMemFree, CLSCTX_INPROC_SERVER},
             Diagnostics::ToolHelp::{
                 CreateToolhelp32Snapshot, Process32First, Process32Next, PROCESSENTRY32,
                 TH32CS_SNAPALL,
             },
-            SystemInformation::GetSystemDirectoryW,
+            RestartManager::{
+                RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+                RM_PROCESS_INFO,
+            },
+            Registry::{RegCloseKey, RegOpenKeyExW, RegQueryInfoKeyW, HKEY, HKEY_LOCAL_MACHINE, KEY_READ},
+            SystemInformation::{GetSystemDirectoryW, GetTickCount64},
+            Threading::{GetExitCodeProcess, WaitForSingleObject, INFINITE},
+        },
+        Storage::FileSystem::{
+            GetCompressedFileSizeW, GetDiskFreeSpaceExW, GetVolumePathNameW, SetFileAttributesW, FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_READONLY,
+            FILE_FLAGS_AND_ATTRIBUTES, INVALID_FILE_SIZE
         },
         UI::{
             Shell::{
-                FileOpenDialog, IFileOpenDialog, IShellItem, SHCreateItemFromParsingName,
-                FOS_FILEMUSTEXIST, FOS_PICKFOLDERS, SIGDN_FILESYSPATH,
+                FileOpenDialog, IFileOpenDialog, IShellItem, SHCreateItemFromParsingName, SHFileOperationW, ShellExecuteExW, ShellExecuteW, IsUserAnAdmin,
+                FOF_ALLOWUNDO, FOF_NOCONFIRMATION, FOF_NOERRORUI, FOF_SILENT, FOS_FILEMUSTEXIST, FOS_PICKFOLDERS,
+                FO_DELETE, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW, SHFILEOPSTRUCTW, SIGDN_FILESYSPATH,
             },
-            WindowsAndMessaging::{GetDesktopWindow, GetWindowRect, SetWindowPos, SWP_NOSIZE},
+            WindowsAndMessaging::{GetDesktopWindow, GetWindowRect, SetWindowPos, SWP_NOSIZE, SW_SHOWNORMAL},
         },
     },
 };
 
+pub fn log_path() -> PathBuf {
+    std::env::temp_dir().join(LOG_FILE_NAME)
+}
+
+/// Appends a timestamped line to the log in the temp dir. There's no console in GUI mode,
+/// so this is where details that aren't worth a dialog go.
+pub fn log(message: &str) {
+    let entry = format!("{}\t{}\n", chrono::Local::now().to_rfc3339(), message);
+    _ = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .and_then(|mut file| file.write_all(entry.as_bytes()));
+}
+
 pub trait RECTExt {
     fn dimensions(&self) -> (i32, i32);
 }
@@ -52,6 +78,9 @@ pub fn center_window(window: HWND) -> Result<(), windows::core::Error> {
     Ok(())
 }
 
+const LANG_NEUTRAL_UNICODE: Language = Language { lang_id: 0x0000, charset_id: 0x04b0 };
+const LOG_FILE_NAME: &str = "hachimi_installer.log";
+
 pub fn read_pe_version_info<'a>(image: &'a [u8]) -> Option<VersionInfo<'a>> {
     pelite::PeFile::from_bytes(image)
         .ok()?
@@ -61,6 +90,29 @@ pub fn read_pe_version_info<'a>(image: &'a [u8]) -> Option<VersionInfo<'a>> {
         .ok()
 }
 
+/// Looks up a string in the version info. DLLs aren't always built with a language neutral string table,
+/// so the languages listed in its translation table are tried next, then any string table in it.
+pub fn read_version_string(version_info: VersionInfo, key: &str) -> Option<String> {
+    if let Some(value) = version_info.value(LANG_NEUTRAL_UNICODE, key) {
+        return Some(value);
+    }
+    if let Some(value) = version_info.translation().iter().find_map(|lang| version_info.value(*lang, key)) {
+        return Some(value);
+    }
+
+    // Lowest language id first, so the result doesn't depend on the map's order
+    version_info.file_info().strings
+        .into_iter()
+        .filter_map(|(lang, strings)| Some(((lang.lang_id, lang.charset_id), strings.get(key)?.clone())))
+        .min_by_key(|(lang, _)| *lang)
+        .map(|(_, value)| value)
+}
+
+/// The PE header's Machine field, e.g. IMAGE_FILE_MACHINE_AMD64.
+pub fn read_pe_machine(image: &[u8]) -> Option<u16> {
+    Some(pelite::PeFile::from_bytes(image).ok()?.file_header().Machine)
+}
+
 pub fn open_select_folder_dialog<P: AsRef<Path>>(
     owner: HWND,
     default_folder: Option<P>,
@@ -74,20 +126,25 @@ pub fn open_select_folder_dialog<P: AsRef<Path>>(
             .SetOptions(FOS_FILEMUSTEXIST | FOS_PICKFOLDERS)
             .ok()?;
 
+        // The default folder is only a hint, the dialog can still be shown if it no longer exists
         if let Some(path) = default_folder {
-            let default_folder_item: IShellItem =
-                SHCreateItemFromParsingName(&HSTRING::from(path.as_ref().to_str().unwrap()), None)
-                    .ok()?;
-            dialog.SetDefaultFolder(&default_folder_item).ok()?;
+            let default_folder_item: Option<IShellItem> =
+                SHCreateItemFromParsingName(&HSTRING::from(path.as_ref()), None).ok();
+            if let Some(item) = default_folder_item {
+                _ = dialog.SetDefaultFolder(&item);
+            }
         }
 
+        // Fails with ERROR_CANCELLED if the user closes the dialog
         dialog.Show(owner).ok()?
     }
 
     let result = unsafe { dialog.GetResult().ok()? };
     let path = unsafe { result.GetDisplayName(SIGDN_FILESYSPATH).ok()? };
-    let path_str = unsafe { path.to_string().unwrap() };
-    Some(path_str.into())
+    // Keep the path as UTF-16 so that it round-trips even if it isn't valid Unicode
+    let path_buf = PathBuf::from(OsString::from_wide(unsafe { path.as_wide() }));
+    unsafe { CoTaskMemFree(Some(path.0 as _)) };
+    Some(path_buf)
 }
 
 pub fn is_game_running() -> bool {
@@ -111,12 +168,97 @@ pub fn is_game_running() -> bool {
 }
 
 pub fn is_specific_process_running(exe_name: &str) -> bool {
-    let Ok(exe_name_cstr) = CString::new(exe_name) else {
+    is_any_process_running(&[exe_name])
+}
+
+/// Exe names are compared case-insensitively, like Windows does.
+fn is_any_process_running(exe_names: &[&str]) -> bool {
+    let Ok(snapshot) = (unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPALL, 0) }) else {
         return false;
     };
+    let mut entry = PROCESSENTRY32::default();
+    entry.dwSize = std::mem::size_of::<PROCESSENTRY32>() as u32;
+    let mut res = unsafe { Process32First(snapshot, &mut entry) };
+
+    let mut found = false;
+    while res.is_ok() {
+        let process_name = unsafe { CStr::from_ptr(entry.szExeFile.as_ptr()) }.to_string_lossy();
+        if exe_names.iter().any(|exe_name| process_name.eq_ignore_ascii_case(exe_name)) {
+            found = true;
+            break;
+        }
+        res = unsafe { Process32Next(snapshot, &mut entry) };
+    }
+
+    _ = unsafe { CloseHandle(snapshot) };
+    found
+}
+
+/// Length in bytes of the JSON array `s` starts with, up to and including its closing bracket.
+pub fn find_json_array_len(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Copies a file or a whole directory tree, replacing files that already exist at `dst`.
+/// Paths that `exclude` returns true for are skipped, along with everything in them.
+pub fn copy_recursive(src: &Path, dst: &Path, exclude: &dyn Fn(&Path) -> bool) -> std::io::Result<()> {
+    if exclude(src) {
+        return Ok(());
+    }
+    if src.is_dir() {
+        std::fs::create_dir_all(dst)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dst.join(entry.file_name()), exclude)?;
+        }
+    } else {
+        clear_readonly(dst)?;
+        std::fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Steam rewrites the app manifests while it's running, which can undo changes made to them.
+pub fn is_steam_running() -> bool {
+    is_specific_process_running("steam.exe")
+}
+
+/// DMM Game Player keeps the game folder locked while it's running or updating the game.
+pub fn is_dmm_launcher_running() -> bool {
+    // The launcher is known to run under either name
+    is_any_process_running(&["DMMGamePlayer.exe", "dmmgameplayer5.exe"])
+}
 
+/// Exe names of the running processes by PID, from a single snapshot.
+fn get_process_names() -> HashMap<u32, String> {
+    let mut names = HashMap::new();
     let Ok(snapshot) = (unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPALL, 0) }) else {
-        return false;
+        return names;
     };
     let mut entry = PROCESSENTRY32::default();
     entry.dwSize = std::mem::size_of::<PROCESSENTRY32>() as u32;
@@ -124,31 +266,384 @@ pub fn is_specific_process_running(exe_name: &str) -> bool {
 
     while res.is_ok() {
         let process_name = unsafe { CStr::from_ptr(entry.szExeFile.as_ptr()) };
-        if process_name == exe_name_cstr.as_c_str() {
-            return true;
-        }
+        names.insert(entry.th32ProcessID, process_name.to_string_lossy().into_owned());
         res = unsafe { Process32Next(snapshot, &mut entry) };
     }
 
-    false
+    _ = unsafe { CloseHandle(snapshot) };
+    names
+}
+
+/// Uses the Restart Manager to find the processes that currently have `path` open.
+pub fn get_locking_processes(path: &Path) -> Vec<String> {
+    let mut session = 0u32;
+    let mut session_key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+    if unsafe { RmStartSession(&mut session, 0, PWSTR(session_key.as_mut_ptr())) } != ERROR_SUCCESS {
+        return Vec::new();
+    }
+
+    let mut processes = Vec::new();
+    let path_hstr = HSTRING::from(path);
+    if unsafe { RmRegisterResources(session, Some(&[PCWSTR(path_hstr.as_ptr())]), None, None) } == ERROR_SUCCESS {
+        let mut infos: Vec<RM_PROCESS_INFO> = Vec::new();
+        let mut needed = 0u32;
+        let mut reasons = 0u32;
+
+        // The list can grow between calls, so retry a few times
+        for _ in 0..3 {
+            infos.resize(needed as usize, RM_PROCESS_INFO::default());
+            let mut count = infos.len() as u32;
+            let res = unsafe { RmGetList(session, &mut needed, &mut count, Some(infos.as_mut_ptr()), &mut reasons) };
+            if res == ERROR_MORE_DATA {
+                continue;
+            }
+            if res == ERROR_SUCCESS {
+                let process_names = get_process_names();
+                processes = infos[..count as usize].iter()
+                    .map(|info| process_names.get(&info.Process.dwProcessId).cloned().unwrap_or_else(|| {
+                        let len = info.strAppName.iter().position(|&c| c == 0).unwrap_or(info.strAppName.len());
+                        String::from_utf16_lossy(&info.strAppName[..len])
+                    }))
+                    .collect();
+            }
+            break;
+        }
+    }
+
+    unsafe { RmEndSession(session) };
+    processes
 }
 
 pub fn get_system_directory() -> PathBuf {
-    let mut buffer = [0u16; MAX_PATH as usize];
-    let length = unsafe { GetSystemDirectoryW(Some(&mut buffer)) };
-    PathBuf::from(OsString::from_wide(&buffer[0..length as usize]))
+    // Without a buffer it returns the size needed, including the null terminator
+    let size = unsafe { GetSystemDirectoryW(None) };
+    let mut buffer = vec![0u16; size as usize];
+    let length = unsafe { GetSystemDirectoryW(Some(&mut buffer)) } as usize;
+    PathBuf::from(OsString::from_wide(&buffer[..length.min(buffer.len())]))
+}
+
+/// Adds the `\\?\` prefix to an absolute path, so that Windows APIs called directly accept it
+/// even if it's longer than MAX_PATH. std::fs already does this by itself. Paths it can't safely
+/// be added to, e.g. relative ones or ones containing `..`, are returned unchanged.
+pub fn extended_length_path(path: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    if path.components().any(|component| component == Component::ParentDir) {
+        return path.to_path_buf();
+    }
+    // The prefix turns off normalization, so drop `.` and repeated separators by rebuilding the path
+    let normalized: PathBuf = path.components().collect();
+    let Some(path_str) = normalized.to_str() else {
+        return path.to_path_buf();
+    };
+    let path_str = path_str.replace('/', "\\");
+
+    match normalized.components().next() {
+        Some(Component::Prefix(prefix)) if normalized.has_root() => match prefix.kind() {
+            Prefix::Disk(_) => PathBuf::from(format!(r"\\?\{}", path_str)),
+            Prefix::UNC(..) => PathBuf::from(format!(r"\\?\UNC\{}", &path_str[2..])),
+            _ => path.to_path_buf()
+        },
+        _ => path.to_path_buf()
+    }
+}
+
+/// Clears the read-only attribute so the file can be overwritten or deleted. Missing files are ignored.
+pub fn clear_readonly(path: &Path) -> std::io::Result<()> {
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Ok(());
+    };
+
+    let attributes = metadata.file_attributes();
+    if attributes & FILE_ATTRIBUTE_READONLY.0 != 0 {
+        unsafe {
+            SetFileAttributesW(&HSTRING::from(extended_length_path(path).as_path()), FILE_FLAGS_AND_ATTRIBUTES(attributes & !FILE_ATTRIBUTE_READONLY.0))?
+        };
+    }
+
+    Ok(())
+}
+
+/// Whether a key under HKLM was written to after the system booted, e.g. to detect
+/// settings that are only read at boot and haven't taken effect yet.
+pub fn is_hklm_key_modified_since_boot(subkey: &str) -> bool {
+    let mut key = HKEY::default();
+    if unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, &HSTRING::from(subkey), 0, KEY_READ, &mut key) }.is_err() {
+        return false;
+    }
+
+    let mut last_write = FILETIME::default();
+    let res = unsafe {
+        RegQueryInfoKeyW(key, PWSTR::null(), None, None, None, None, None, None, None, None, None, Some(&mut last_write))
+    };
+    unsafe { _ = RegCloseKey(key) };
+    if res.is_err() {
+        return false;
+    }
+
+    // FILETIMEs count 100ns intervals since 1601-01-01
+    const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+    let Ok(since_unix_epoch) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) else {
+        return false;
+    };
+    let now = UNIX_EPOCH_AS_FILETIME + (since_unix_epoch.as_nanos() / 100) as u64;
+    let boot_time = now.saturating_sub(unsafe { GetTickCount64() } * 10_000);
+    let last_write = ((last_write.dwHighDateTime as u64) << 32) | last_write.dwLowDateTime as u64;
+
+    last_write > boot_time
+}
+
+/// Whether NTFS compression is enabled for a file or folder.
+pub fn is_compressed(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_COMPRESSED.0 != 0)
+}
+
+/// The space a file takes up on disk, which is less than its length if it's compressed.
+pub fn get_allocated_size(path: &Path) -> Option<u64> {
+    let mut high = 0u32;
+    let low = unsafe { GetCompressedFileSizeW(&HSTRING::from(extended_length_path(path).as_path()), Some(&mut high)) };
+    if low == INVALID_FILE_SIZE && unsafe { GetLastError() } != ERROR_SUCCESS {
+        return None;
+    }
+    Some(((high as u64) << 32) | low as u64)
+}
+
+/// Whether files can be created in `dir`. This is checked by creating one, since Windows
+/// ignores the read-only attribute on folders, while permissions and locked volumes still apply.
+pub fn is_dir_writable(dir: &Path) -> bool {
+    let path = dir.join("hachimi_installer_write_test.tmp");
+    let res = File::create(&path);
+    _ = std::fs::remove_file(&path);
+    res.is_ok()
+}
+
+/// Formats a size in bytes with the largest unit that fits, e.g. "150.0 MB".
+pub fn format_size(bytes: u64) -> String {
+    const KB: f64 = 1024.0;
+    let bytes_f = bytes as f64;
+    if bytes_f < KB {
+        t!("util.size.bytes", size = bytes)
+    } else if bytes_f < KB * KB {
+        t!("util.size.kb", size = format!("{:.1}", bytes_f / KB))
+    } else if bytes_f < KB * KB * KB {
+        t!("util.size.mb", size = format!("{:.1}", bytes_f / (KB * KB)))
+    } else {
+        t!("util.size.gb", size = format!("{:.1}", bytes_f / (KB * KB * KB)))
+    }
+}
+
+/// Bytes available to the current user on the drive containing `dir`.
+pub fn get_free_disk_space(dir: &Path) -> Option<u64> {
+    let mut available = 0u64;
+    unsafe { GetDiskFreeSpaceExW(&HSTRING::from(dir), Some(&mut available), None, None) }.ok()?;
+    Some(available)
+}
+
+/// Roughly measures how fast `dir` can be written to, in bytes per second,
+/// by writing a few MB to a temporary file.
+pub fn measure_write_speed(dir: &Path) -> Option<u64> {
+    const SIZE: usize = 8 * 1024 * 1024;
+    let path = dir.join("hachimi_installer_benchmark.tmp");
+    let data = vec![0u8; SIZE];
+
+    let start = std::time::Instant::now();
+    let res = (|| {
+        let mut file = File::create(&path)?;
+        file.write_all(&data)?;
+        file.sync_all()
+    })();
+    let elapsed = start.elapsed();
+    _ = std::fs::remove_file(&path);
+    res.ok()?;
+
+    Some((SIZE as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+}
+
+/// `<name>.tmp` in `temp_dir`, or next to `path` if it's not set.
+pub fn temp_path_for(path: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    match temp_dir {
+        Some(temp_dir) => temp_dir.join(tmp_name),
+        None => path.with_file_name(tmp_name)
+    }
+}
+
+/// The mount point of the volume `path` is on, e.g. `C:\`.
+fn get_volume_path(path: &Path) -> Option<OsString> {
+    let mut buffer = [0u16; MAX_PATH as usize + 1];
+    unsafe { GetVolumePathNameW(&HSTRING::from(path), &mut buffer) }.ok()?;
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    Some(OsString::from_wide(&buffer[..len]))
+}
+
+/// Whether `a` and `b` are on the same volume, so a file can be renamed from one to the other.
+/// Assumed to be the case if it can't be determined.
+pub fn is_same_volume(a: &Path, b: &Path) -> bool {
+    match (get_volume_path(a), get_volume_path(b)) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+        _ => true
+    }
+}
+
+/// Renames `from` over `to`. If they are on different volumes, copies it instead,
+/// in which case the replacement isn't atomic.
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            log(&format!("{} is on a different volume than {}, copying it instead", from.display(), to.display()));
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        },
+        res => res
+    }
+}
+
+/// Writes to a temp file and moves it over `path` once it's on disk,
+/// so an interrupted write never leaves a partial file behind.
+pub fn write_file_atomic(path: &Path, data: &[u8], temp_dir: Option<&Path>) -> std::io::Result<()> {
+    let tmp_path = temp_path_for(path, temp_dir);
+
+    let res = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+        move_file(&tmp_path, path)
+    })();
+    if res.is_err() {
+        _ = std::fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+pub fn is_elevated() -> bool {
+    unsafe { IsUserAnAdmin() }.as_bool()
+}
+
+/// Quotes an argument so that CommandLineToArgvW parses it back unchanged. Works on UTF-16
+/// code units, since arguments such as paths don't have to be valid Unicode.
+fn quote_arg(arg: &OsStr) -> Vec<u16> {
+    const BACKSLASH: u16 = b'\\' as u16;
+    const QUOTE: u16 = b'"' as u16;
+
+    let arg: Vec<u16> = arg.encode_wide().collect();
+    if !arg.is_empty() && !arg.iter().any(|c| [b' ' as u16, b'\t' as u16, QUOTE].contains(c)) {
+        return arg;
+    }
+
+    let mut quoted = vec![QUOTE];
+    let mut backslashes = 0;
+    for c in arg {
+        match c {
+            BACKSLASH => {
+                backslashes += 1;
+                continue;
+            },
+            // Backslashes are only special before a quote
+            QUOTE => quoted.extend(std::iter::repeat_n(BACKSLASH, backslashes * 2 + 1)),
+            _ => quoted.extend(std::iter::repeat_n(BACKSLASH, backslashes))
+        }
+        backslashes = 0;
+        quoted.push(c);
+    }
+    quoted.extend(std::iter::repeat_n(BACKSLASH, backslashes * 2));
+    quoted.push(QUOTE);
+    quoted
+}
+
+/// Starts this executable again as administrator with the same arguments, waits for it to finish,
+/// then exits with its exit code, so scripts waiting on this process see the real result.
+/// Only returns if it couldn't be started, e.g. because the user declined the UAC prompt.
+pub fn relaunch_elevated() -> std::io::Error {
+    let exe_path = match std::env::current_exe() {
+        Ok(path) => path,
+        Err(e) => return e
+    };
+    let mut params: Vec<u16> = Vec::new();
+    for arg in std::env::args_os().skip(1) {
+        if !params.is_empty() {
+            params.push(b' ' as u16);
+        }
+        params.extend(quote_arg(&arg));
+    }
+    params.push(0);
+
+    let exe_path_hstr = HSTRING::from(exe_path.as_path());
+    let mut info = SHELLEXECUTEINFOW {
+        cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
+        fMask: SEE_MASK_NOCLOSEPROCESS,
+        lpVerb: w!("runas"),
+        lpFile: PCWSTR(exe_path_hstr.as_ptr()),
+        lpParameters: PCWSTR(params.as_ptr()),
+        nShow: SW_SHOWNORMAL.0,
+        ..Default::default()
+    };
+    if let Err(e) = unsafe { ShellExecuteExW(&mut info) } {
+        return e.into();
+    }
+    if info.hProcess.is_invalid() {
+        std::process::exit(0);
+    }
+
+    let mut exit_code = 1;
+    unsafe {
+        WaitForSingleObject(info.hProcess, INFINITE);
+        _ = GetExitCodeProcess(info.hProcess, &mut exit_code);
+        _ = CloseHandle(info.hProcess);
+    }
+    std::process::exit(exit_code as i32);
+}
+
+/// Opens a folder in Explorer.
+pub fn open_folder(dir: &Path) {
+    unsafe { ShellExecuteW(None, None, &HSTRING::from(dir), None, None, SW_SHOWNORMAL) };
 }
 
-pub fn verify_file_hash(path: &Path, expected_hash: &str) -> Result<(), String> {
+pub fn move_to_recycle_bin(path: &Path) -> std::io::Result<()> {
+    // pFrom is a list of paths, terminated by an extra null
+    let mut from: Vec<u16> = path.as_os_str().encode_wide().collect();
+    from.extend_from_slice(&[0, 0]);
+
+    let mut op = SHFILEOPSTRUCTW {
+        wFunc: FO_DELETE,
+        pFrom: PCWSTR(from.as_ptr()),
+        fFlags: (FOF_ALLOWUNDO | FOF_NOCONFIRMATION | FOF_SILENT | FOF_NOERRORUI).0 as u16,
+        ..Default::default()
+    };
+    let res = unsafe { SHFileOperationW(&mut op) };
+    // The result is one of the shell's DE_* codes rather than a Win32 error code
+    if res != 0 {
+        return Err(std::io::Error::other(format!("SHFileOperationW failed with code {:#x}", res)));
+    }
+    if op.fAnyOperationsAborted.as_bool() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "Recycle operation was aborted"));
+    }
+
+    Ok(())
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the lowercase hex SHA-256 of a file, or a displayable error.
+/// `progress` is called with the bytes hashed so far and the file size.
+pub fn hash_file(path: &Path, cancel: &AtomicBool, progress: &mut dyn FnMut(u64, u64)) -> Result<String, String> {
     let mut file = match File::open(path) {
         Ok(f) => f,
         Err(e) => return Err(t!("details.hash_error.open_file", error = e.to_string())),
     };
+    let total = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let mut done = 0;
 
     let mut hasher = Sha256::new();
     let mut buffer = [0; 1024];
 
     loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(t!("details.hash_error.cancelled"));
+        }
         let n = match file.read(&mut buffer) {
             Ok(n) => n,
             Err(e) => return Err(t!("details.hash_error.read_file", error = e.to_string())),
@@ -157,31 +652,142 @@ pub fn verify_file_hash(path: &Path, expected_hash: &str) -> Result<(), String>
             break;
         }
         hasher.update(&buffer[..n]);
+        done += n as u64;
+        progress(done, total);
     }
 
-    let found_hash = format!("{:x}", hasher.finalize());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Bytes read from the start, middle and end of the file for `fingerprint_file`
+const FINGERPRINT_HEADER_SIZE: u64 = 4096;
+const FINGERPRINT_RANGE_SIZE: u64 = 64 * 1024;
 
-    if found_hash.to_lowercase() == expected_hash.to_lowercase() {
-        Ok(())
-    } else {
-        Err(t!(
-            "details.hash_error.mismatch",
-            expected = expected_hash,
-            found = found_hash
-        ))
+/// Returns the lowercase hex SHA-256 of the file size, the PE headers (which include the link
+/// timestamp and checksum) and a few fixed ranges of a file. Much faster than `hash_file` for
+/// large executables, but only good for telling builds apart, not for verifying them.
+pub fn fingerprint_file(path: &Path) -> Result<String, String> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(t!("details.hash_error.open_file", error = e.to_string())),
+    };
+    let size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Err(t!("details.hash_error.read_file", error = e.to_string())),
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    let ranges = [
+        (0, FINGERPRINT_HEADER_SIZE),
+        ((size / 2).saturating_sub(FINGERPRINT_RANGE_SIZE / 2), FINGERPRINT_RANGE_SIZE),
+        (size.saturating_sub(FINGERPRINT_RANGE_SIZE), FINGERPRINT_RANGE_SIZE)
+    ];
+    let mut buffer = Vec::new();
+    for (offset, len) in ranges {
+        buffer.clear();
+        let res = file.seek(SeekFrom::Start(offset))
+            .and_then(|_| (&mut file).take(len).read_to_end(&mut buffer));
+        if let Err(e) = res {
+            return Err(t!("details.hash_error.read_file", error = e.to_string()));
+        }
+        hasher.update(&buffer);
     }
+
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
 pub fn apply_patch(
     original_data: &[u8],
     patch_data: &[u8],
     output_path: &Path,
+    cancel: &AtomicBool,
+    progress: &mut dyn FnMut(u64, u64),
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut new_exe_data = Vec::new();
     bsdiff::patch(original_data, &mut std::io::Cursor::new(patch_data), &mut new_exe_data)?;
 
     let mut temp_exe_file = File::create(output_path)?;
-    temp_exe_file.write_all(&new_exe_data)?;
+    let total = new_exe_data.len() as u64;
+    let mut done = 0;
+    for chunk in new_exe_data.chunks(1024 * 1024) {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into());
+        }
+        temp_exe_file.write_all(chunk)?;
+        done += chunk.len() as u64;
+        progress(done, total);
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_readonly(path: &Path) {
+        let mut permissions = std::fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions).unwrap();
+    }
+
+    #[test]
+    fn read_only_file_can_be_overwritten_after_clearing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("UnityPlayer.dll");
+        std::fs::write(&path, b"old").unwrap();
+        set_readonly(&path);
+
+        clear_readonly(&path).unwrap();
+        write_file_atomic(&path, b"new", None).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!std::fs::metadata(&path).unwrap().permissions().readonly());
+    }
+
+    #[test]
+    fn clear_readonly_ignores_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        clear_readonly(&dir.path().join("missing.dll")).unwrap();
+    }
+
+    #[test]
+    fn extended_length_path_prefixes_absolute_paths() {
+        assert_eq!(extended_length_path(Path::new(r"C:\Games\.\Umamusume")), Path::new(r"\\?\C:\Games\Umamusume"));
+        assert_eq!(extended_length_path(Path::new(r"\\server\share\Umamusume")), Path::new(r"\\?\UNC\server\share\Umamusume"));
+
+        let name = "a".repeat(50);
+        let long_path = PathBuf::from(format!(r"C:\{}", [name.as_str(); 6].join(r"\")));
+        assert!(long_path.as_os_str().len() > 260);
+        assert_eq!(extended_length_path(&long_path), PathBuf::from(format!(r"\\?\{}", long_path.display())));
+    }
+
+    #[test]
+    fn extended_length_path_keeps_paths_it_cannot_prefix() {
+        for path in [r"Games\Umamusume", r"\\?\C:\Games\Umamusume", r"C:\Games\..\Umamusume", r"C:Umamusume"] {
+            assert_eq!(extended_length_path(Path::new(path)), Path::new(path));
+        }
+    }
+
+    #[test]
+    fn files_in_deep_paths_can_be_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let name = "a".repeat(50);
+        let deep_dir = [name.as_str(); 6].iter().fold(dir.path().to_path_buf(), |path, name| path.join(name));
+        let path = deep_dir.join("UnityPlayer.dll");
+        assert!(path.as_os_str().len() > 260);
+
+        std::fs::create_dir_all(&deep_dir).unwrap();
+        write_file_atomic(&path, b"old", None).unwrap();
+        set_readonly(&path);
+        clear_readonly(&path).unwrap();
+        write_file_atomic(&path, b"new", None).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn system_directory_exists() {
+        assert!(get_system_directory().is_dir());
+    }
+}